@@ -0,0 +1,32 @@
+/*
+ * Generates the ABI from the contract sources on every build, into OUT_DIR.
+ *
+ * The build does not write to the source tree. The checked-in abi.json is
+ * updated with `cargo run -p nep9000_abi --bin write_abi` and a test checks it is current.
+ */
+
+#![allow(clippy::needless_return)]
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+#[path = "src/generator.rs"]
+#[allow(dead_code)]
+mod generator;
+
+fn main() {
+    let crate_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+    let contract_dir = crate_dir.parent().unwrap();
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=src/generator.rs");
+    println!("cargo:rerun-if-changed={}", contract_dir.join("token/src").display());
+    println!("cargo:rerun-if-changed={}", contract_dir.join("pool/src").display());
+    println!("cargo:rerun-if-changed={}", contract_dir.join("interface/src").display());
+
+    let abi = generator::generate(contract_dir);
+    let json = serde_json::to_string_pretty(&abi).unwrap() + "\n";
+
+    let path = PathBuf::from(env::var("OUT_DIR").unwrap()).join("abi.json");
+    fs::write(&path, json).unwrap();
+}
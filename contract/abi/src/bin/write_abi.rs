@@ -0,0 +1,15 @@
+/*
+ * Writes the ABI of the current contract sources to contract/abi/abi.json,
+ * the copy src/abi.js loads:
+ *
+ *     cargo run -p nep9000_abi --bin write_abi
+ */
+
+use std::fs;
+use std::path::Path;
+
+fn main() {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("abi.json");
+    fs::write(&path, nep9000_abi::ABI_JSON).unwrap();
+    println!("Wrote {}", path.display());
+}
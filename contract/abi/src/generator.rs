@@ -0,0 +1,290 @@
+/*
+ * Reads the contract ABI from the Rust sources.
+ *
+ * Follows the same rules as near_bindgen: public methods of #[near_bindgen] impls are exported,
+ * methods taking `&mut self` and #[init] methods are change methods, everything else is a view.
+ *
+ * Argument and result types are described with a small JSON schema:
+ *
+ *     { "type": "string", "format": "uint128" }     U128, a decimal string
+ *     { "type": "integer", "format": "uint128" }    Balance, a JSON number that does not fit a JS number
+ *     { "type": "array", "items": ... }             Vec<T>, or a list of item schemas for tuples
+ *     { ..., "nullable": true }                     Option<T>
+ *     { "title": "Metadata" }                       Types of the contract, see their Rust definition
+ *
+ * Shared by the build script and the library, so it depends only on syn, quote and serde.
+ */
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{ Path, PathBuf };
+
+use quote::ToTokens;
+use serde::{ Deserialize, Serialize };
+use serde_json::{ json, Value };
+use syn::{ Attribute, FnArg, GenericArgument, ImplItem, Item, PathArguments, ReturnType, Signature, TraitItem, Type, Visibility };
+
+/// ABI of the contracts in this workspace
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct Abi {
+    pub token: ContractAbi,
+    pub pool: ContractAbi,
+
+    /// Methods a contract must have to receive tokens with send()
+    pub receiver: ContractAbi,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct ContractAbi {
+
+    /// The Rust struct or trait the methods were read from
+    pub name: String,
+
+    /// Sorted by name
+    pub methods: Vec<MethodAbi>,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct MethodAbi {
+    pub name: String,
+    pub view: bool,
+    pub init: bool,
+    pub payable: bool,
+    pub args: Vec<ArgAbi>,
+
+    // None if the method returns nothing
+    pub returns: Option<TypeAbi>,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct ArgAbi {
+    pub name: String,
+
+    #[serde(rename = "type")]
+    pub ty: TypeAbi,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct TypeAbi {
+
+    /// The type as written in the contract
+    pub rust: String,
+
+    /// How the value looks like in the JSON arguments or result
+    pub json: Value,
+}
+
+impl ContractAbi {
+
+    pub fn get_method(&self, name: &str) -> Option<&MethodAbi> {
+        return self.methods.iter().find(|m| m.name == name);
+    }
+
+    pub fn view_methods(&self) -> Vec<String> {
+        return self.methods.iter().filter(|m| m.view).map(|m| m.name.clone()).collect();
+    }
+
+    pub fn change_methods(&self) -> Vec<String> {
+        return self.methods.iter().filter(|m| !m.view).map(|m| m.name.clone()).collect();
+    }
+}
+
+/// Read the ABI of the token, the pool and the receiver interface from the `contract` folder
+pub fn generate(contract_dir: &Path) -> Abi {
+    let token = parse_dir(&contract_dir.join("token/src"));
+    let pool = parse_dir(&contract_dir.join("pool/src"));
+    let interface = parse_dir(&contract_dir.join("interface/src"));
+    return Abi {
+        token: contract_abi(&token, "Token"),
+        pool: contract_abi(&pool, "BurnerPool"),
+        receiver: trait_abi(&interface, "Receiver"),
+    };
+}
+
+/// Parse all .rs files of a folder, in file name order
+pub fn parse_dir(dir: &Path) -> Vec<syn::File> {
+    let mut paths: Vec<PathBuf> = fs::read_dir(dir)
+        .unwrap_or_else(|e| panic!("Cannot read {}: {}", dir.display(), e))
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "rs"))
+        .collect();
+    paths.sort();
+    return paths.iter().map(|path| {
+        let source = fs::read_to_string(path).unwrap();
+        syn::parse_file(&source).unwrap_or_else(|e| panic!("Cannot parse {}: {}", path.display(), e))
+    }).collect();
+}
+
+/// Methods exported by the #[near_bindgen] impls of `type_name`, including its trait impls
+pub fn contract_abi(files: &[syn::File], type_name: &str) -> ContractAbi {
+    let aliases = type_aliases(files);
+    let mut methods = vec![];
+    for item in files.iter().flat_map(|file| file.items.iter()) {
+        let item_impl = match item {
+            Item::Impl(x) if has_attr(&x.attrs, "near_bindgen") && last_ident(&x.self_ty) == Some(type_name.to_string()) => x,
+            _ => continue,
+        };
+        for impl_item in &item_impl.items {
+            if let ImplItem::Method(method) = impl_item {
+                // All methods of a trait impl are exported
+                let exported = match method.vis {
+                    Visibility::Public(_) => true,
+                    _ => item_impl.trait_.is_some(),
+                };
+                if exported {
+                    methods.push(method_abi(&method.sig, &method.attrs, &aliases));
+                }
+            }
+        }
+    }
+    return sorted(type_name, methods);
+}
+
+/// Methods of the trait `trait_name`, e.g. one declared with #[ext_contract]
+pub fn trait_abi(files: &[syn::File], trait_name: &str) -> ContractAbi {
+    let aliases = type_aliases(files);
+    let mut methods = vec![];
+    for item in files.iter().flat_map(|file| file.items.iter()) {
+        if let Item::Trait(item_trait) = item {
+            if item_trait.ident != trait_name {
+                continue;
+            }
+            for trait_item in &item_trait.items {
+                if let TraitItem::Method(method) = trait_item {
+                    methods.push(method_abi(&method.sig, &method.attrs, &aliases));
+                }
+            }
+        }
+    }
+    return sorted(trait_name, methods);
+}
+
+fn sorted(name: &str, mut methods: Vec<MethodAbi>) -> ContractAbi {
+    methods.sort_by(|a, b| a.name.cmp(&b.name));
+    return ContractAbi { name: name.to_string(), methods };
+}
+
+fn method_abi(sig: &Signature, attrs: &[Attribute], aliases: &HashMap<String, Type>) -> MethodAbi {
+    let init = has_attr(attrs, "init");
+    let mut receiver = None;
+    let mut args = vec![];
+    for input in &sig.inputs {
+        match input {
+            FnArg::Receiver(r) => receiver = Some(r),
+            FnArg::Typed(pat_type) => {
+                // Callback results come from the promise, not from the arguments
+                if has_attr(&pat_type.attrs, "callback") || has_attr(&pat_type.attrs, "callback_vec") {
+                    continue;
+                }
+                args.push(ArgAbi {
+                    name: pat_type.pat.to_token_stream().to_string(),
+                    ty: type_abi(&pat_type.ty, aliases),
+                });
+            },
+        }
+    }
+    let view = match receiver {
+        Some(r) => r.mutability.is_none(),
+        None => !init,
+    };
+    let returns = match &sig.output {
+        ReturnType::Type(_, ty) if !init && !is_unit(ty) => Some(type_abi(ty, aliases)),
+        _ => None,
+    };
+    return MethodAbi {
+        name: sig.ident.to_string(),
+        view,
+        init,
+        payable: has_attr(attrs, "payable"),
+        args,
+        returns,
+    };
+}
+
+fn type_abi(ty: &Type, aliases: &HashMap<String, Type>) -> TypeAbi {
+    return TypeAbi {
+        rust: type_name(ty),
+        json: json_schema(ty, aliases),
+    };
+}
+
+/// `type X = Y;` declarations of the sources, plus the ones near_sdk contracts use
+fn type_aliases(files: &[syn::File]) -> HashMap<String, Type> {
+    let mut aliases: HashMap<String, Type> = HashMap::new();
+    for (name, ty) in &[("Balance", "u128"), ("BlockHeight", "u64"), ("EpochHeight", "u64"), ("Gas", "u64"), ("StorageUsage", "u64")] {
+        aliases.insert(name.to_string(), syn::parse_str(ty).unwrap());
+    }
+    for item in files.iter().flat_map(|file| file.items.iter()) {
+        if let Item::Type(item_type) = item {
+            aliases.insert(item_type.ident.to_string(), (*item_type.ty).clone());
+        }
+    }
+    return aliases;
+}
+
+fn json_schema(ty: &Type, aliases: &HashMap<String, Type>) -> Value {
+    let path = match ty {
+        Type::Reference(r) => return json_schema(&r.elem, aliases),
+        Type::Tuple(t) => {
+            let items: Vec<Value> = t.elems.iter().map(|elem| json_schema(elem, aliases)).collect();
+            return json!({ "type": "array", "items": items });
+        },
+        Type::Path(p) => &p.path,
+        _ => return json!({}),
+    };
+    let segment = path.segments.last().unwrap();
+    let name = segment.ident.to_string();
+    if let Some(alias) = aliases.get(&name) {
+        return json_schema(alias, aliases);
+    }
+    match name.as_str() {
+        "String" | "AccountId" => return json!({ "type": "string" }),
+        "bool" => return json!({ "type": "boolean" }),
+        "u8" | "u16" | "u32" | "u64" | "u128" => return json!({ "type": "integer", "format": format!("uint{}", &name[1..]) }),
+        "U64" | "U128" => return json!({ "type": "string", "format": format!("uint{}", &name[1..]) }),
+        "Base64VecU8" => return json!({ "type": "string", "format": "base64" }),
+        // The result is whatever the promise returns
+        "Promise" => return json!({}),
+        _ => (),
+    }
+    match (name.as_str(), generic_arg(&segment.arguments)) {
+        ("Option", Some(inner)) => {
+            let mut schema = json_schema(inner, aliases);
+            schema["nullable"] = json!(true);
+            return schema;
+        },
+        ("Vec", Some(inner)) => return json!({ "type": "array", "items": json_schema(inner, aliases) }),
+        ("PromiseOrValue", Some(inner)) => return json_schema(inner, aliases),
+        _ => return json!({ "title": name }),
+    }
+}
+
+fn generic_arg(arguments: &PathArguments) -> Option<&Type> {
+    if let PathArguments::AngleBracketed(args) = arguments {
+        if let Some(GenericArgument::Type(ty)) = args.args.first() {
+            return Some(ty);
+        }
+    }
+    return None;
+}
+
+/// The type as it would be written by hand, e.g. `Vec<(AccountId, U128)>`
+fn type_name(ty: &Type) -> String {
+    let tokens: String = ty.to_token_stream().to_string().split_whitespace().collect();
+    return tokens.replace(",", ", ");
+}
+
+fn is_unit(ty: &Type) -> bool {
+    return matches!(ty, Type::Tuple(t) if t.elems.is_empty());
+}
+
+fn last_ident(ty: &Type) -> Option<String> {
+    match ty {
+        Type::Path(p) => return p.path.segments.last().map(|s| s.ident.to_string()),
+        _ => return None,
+    }
+}
+
+fn has_attr(attrs: &[Attribute], name: &str) -> bool {
+    return attrs.iter().any(|attr| attr.path.segments.last().is_some_and(|s| s.ident == name));
+}
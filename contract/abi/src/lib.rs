@@ -0,0 +1,120 @@
+/*
+ * Machine-readable ABI of the token and the burner pool.
+ *
+ * The build script reads the #[near_bindgen] impls of Token and BurnerPool and the Receiver trait
+ * of nep9000_interface. Rust clients get the ABI of the sources they were built from with abi():
+ *
+ *     let abi = nep9000_abi::abi();
+ *     assert!(abi.token.get_method("send").unwrap().payable);
+ *
+ * JavaScript loads the checked-in contract/abi/abi.json in src/abi.js. Edit the contracts, not abi.json,
+ * and update it after changing a contract interface:
+ *
+ *     cargo run -p nep9000_abi --bin write_abi
+ *
+ * test_checked_in_abi_is_current fails while the file is out of date.
+ */
+
+#![allow(clippy::needless_return)]
+
+pub mod generator;
+
+pub use crate::generator::{ Abi, ArgAbi, ContractAbi, MethodAbi, TypeAbi };
+
+/// The ABI generated from the contract sources of this build, as JSON
+pub const ABI_JSON: &str = include_str!(concat!(env!("OUT_DIR"), "/abi.json"));
+
+pub fn abi() -> Abi {
+    return serde_json::from_str(ABI_JSON).expect("abi.json is not valid");
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    use crate::generator::contract_abi;
+
+    #[test]
+    fn test_token_abi() {
+        let abi = abi();
+        assert_eq!(abi.token.name, "Token");
+        assert!(abi.token.get_method("process_bytes").is_none());
+
+        let new = abi.token.get_method("new").unwrap();
+        assert!(new.init && !new.view);
+        assert_eq!(new.returns, None);
+
+        let send = abi.token.get_method("send").unwrap();
+        assert!(send.payable && !send.view);
+        let args: Vec<&str> = send.args.iter().map(|a| a.name.as_str()).collect();
+        assert_eq!(args, vec!["new_owner_id", "amount", "message"]);
+        assert_eq!(send.args[1].ty.json, json!({ "type": "integer", "format": "uint128" }));
+        assert_eq!(send.returns.as_ref().unwrap().json, json!({ "type": "integer", "format": "uint64" }));
+
+        let handle_receiver = abi.token.get_method("handle_receiver").unwrap();
+        assert!(!handle_receiver.view && !handle_receiver.payable);
+
+        assert!(abi.token.view_methods().contains(&String::from("ft_balance_of")));
+        assert!(abi.token.change_methods().contains(&String::from("storage_deposit")));
+    }
+
+    #[test]
+    fn test_pool_and_receiver_abi() {
+        let abi = abi();
+        assert!(abi.pool.get_method("is_receiver").unwrap().view);
+        assert!(abi.pool.get_method("get_total_burned").unwrap().view);
+        assert!(abi.pool.get_method("new").unwrap().init);
+
+        // The pool implements the receiver interface
+        for method in &abi.receiver.methods {
+            assert_eq!(abi.pool.get_method(&method.name).unwrap().args, method.args);
+        }
+        let on_token_received = abi.receiver.get_method("on_token_received").unwrap();
+        assert_eq!(on_token_received.returns.as_ref().unwrap().json, json!({ "title": "ReceiverResult" }));
+    }
+
+    #[test]
+    fn test_checked_in_abi_is_current() {
+        let checked_in = include_str!("../abi.json");
+        assert!(checked_in == ABI_JSON, "contract/abi/abi.json is out of date, run `cargo run -p nep9000_abi --bin write_abi`");
+    }
+
+    #[test]
+    fn test_json_types() {
+        let file = syn::parse_file(r#"
+            pub type TransferId = u64;
+
+            #[near_bindgen]
+            impl Example {
+                pub fn example(&mut self, a: Option<U128>, b: Vec<(AccountId, TransferId)>, #[callback] c: u32) -> PromiseOrValue<Metadata> {}
+                pub fn no_result(&self) {}
+                fn private(&mut self) {}
+            }
+
+            #[near_bindgen]
+            impl Receiver for Example {
+                fn is_receiver() -> bool {}
+            }
+        "#).unwrap();
+        let example = contract_abi(&[file], "Example");
+        assert_eq!(example.methods.len(), 3);
+        assert!(example.get_method("is_receiver").unwrap().view);
+
+        let method = example.get_method("example").unwrap();
+        assert_eq!(method.args.len(), 2);
+        assert_eq!(method.args[0].ty.rust, "Option<U128>");
+        assert_eq!(method.args[0].ty.json, json!({ "type": "string", "format": "uint128", "nullable": true }));
+        assert_eq!(method.args[1].ty.rust, "Vec<(AccountId, TransferId)>");
+        assert_eq!(method.args[1].ty.json, json!({
+            "type": "array",
+            "items": { "type": "array", "items": [{ "type": "string" }, { "type": "integer", "format": "uint64" }] },
+        }));
+        assert_eq!(method.returns.as_ref().unwrap().json, json!({ "title": "Metadata" }));
+
+        let no_result = example.get_method("no_result").unwrap();
+        assert!(no_result.view);
+        assert_eq!(no_result.returns, None);
+    }
+}
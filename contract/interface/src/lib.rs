@@ -0,0 +1,191 @@
+/*
+ * Interface between the token and the contracts receiving it.
+ *
+ * The wire types and constants only need serde, so off-chain clients
+ * and other SDKs can use them without near-sdk. The `borsh` feature adds the Borsh
+ * encoding and the `sdk` feature adds the near-sdk side,
+ * the Receiver traits and the ext_contract promise helpers:
+ *
+ *     nep9000_interface = { path = "../interface", features = ["sdk"] }
+ *
+ * A receiver contract depends on this crate only, not on the token:
+ *
+ *     #[near_bindgen]
+ *     impl Receiver for MyPool {
+ *         fn is_receiver() -> bool { true }
+ *         fn on_token_received(&mut self, ...) -> PromiseOrValue<ReceiverResult> { ... }
+ *     }
+ *
+ * How send() talks to the receiver:
+ *
+ * 1. The token calls is_receiver() on the receiving account. If the call fails,
+ *    e.g. because the account has no contract, the transfer is finalised as a plain transfer.
+ * 2. Otherwise the token calls on_token_received(). The tokens are already in the receiver's balance,
+ *    but locked until the token has seen the ReceiverResult.
+ * 3. The transfer is finalised, partially refunded, forwarded or rolled back based on the result.
+ *    A panic or running out of gas rolls the transfer back with REJECT_RECEIVER_FAILED.
+ *
+ * Without features the crate is no_std and only needs alloc. borsh 0.7, the version
+ * near-sdk 2.0 uses, needs std, so the `borsh` and `sdk` features turn std on.
+ * It holds no state and no global allocator.
+ */
+
+#![cfg_attr(not(any(feature = "borsh", test)), no_std)]
+#![allow(clippy::needless_return)]
+
+extern crate alloc;
+
+use alloc::format;
+use alloc::string::{ String, ToString };
+use alloc::vec::Vec;
+use core::fmt;
+
+#[cfg(feature = "borsh")]
+use borsh::{ BorshDeserialize, BorshSerialize };
+use serde::de::{ self, Deserializer, Visitor };
+use serde::{ Deserialize, Serialize, Serializer };
+
+#[cfg(feature = "sdk")]
+mod sdk;
+
+#[cfg(feature = "sdk")]
+pub use crate::sdk::*;
+
+/// Account names, the same as near_sdk::AccountId
+pub type AccountId = String;
+
+/// Method the token calls to check the receiver can handle send()
+pub const IS_RECEIVER_METHOD: &str = "is_receiver";
+
+/// Method the token calls to notify the receiver, with OnTokenReceivedArgs
+pub const ON_TOKEN_RECEIVED_METHOD: &str = "on_token_received";
+
+/// NEP-141 method ft_transfer_call() calls, with FtOnTransferArgs
+pub const FT_ON_TRANSFER_METHOD: &str = "ft_on_transfer";
+
+/// Reject code when on_token_received panicked or ran out of gas
+pub const REJECT_RECEIVER_FAILED: u32 = 1;
+
+/// Reject code when the token contract could not understand the receiver result
+pub const REJECT_INVALID_RESULT: u32 = 2;
+
+/// Receivers should use reject codes starting from this, codes below it are reserved for the token contract
+pub const REJECT_CODE_CUSTOM: u32 = 1000;
+
+/// A u128 that is a decimal string in JSON, as JavaScript numbers cannot hold it.
+/// The same wire format as near_sdk::json_types::U128.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "borsh", derive(BorshDeserialize, BorshSerialize))]
+pub struct U128(pub u128);
+
+impl From<u128> for U128 {
+    fn from(value: u128) -> Self {
+        return U128(value);
+    }
+}
+
+impl From<U128> for u128 {
+    fn from(value: U128) -> Self {
+        return value.0;
+    }
+}
+
+impl Serialize for U128 {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        return serializer.serialize_str(&self.0.to_string());
+    }
+}
+
+impl<'de> Deserialize<'de> for U128 {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        return deserializer.deserialize_str(U128Visitor);
+    }
+}
+
+struct U128Visitor;
+
+impl<'de> Visitor<'de> for U128Visitor {
+    type Value = U128;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        return formatter.write_str("a u128 as a decimal string");
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<U128, E> {
+        return value.parse().map(U128).map_err(|_| E::custom(format!("invalid u128 {}", value)));
+    }
+}
+
+/**
+ * What the receiving smart contract did with the incoming tokens.
+ *
+ * Returned from on_token_received. The token contract finalises or rolls back
+ * the transfer based on this.
+ */
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "borsh", derive(BorshDeserialize, BorshSerialize))]
+pub enum ReceiverResult {
+
+    /// Receiver keeps all tokens
+    Accepted,
+
+    /// Receiver does not want the tokens and they are returned to the sender
+    Rejected { code: u32, reason: String },
+
+    /// Receiver keeps `amount_used` tokens and the rest is returned to the sender
+    AcceptedPartial { amount_used: U128 },
+
+    /// Receiver sends `amount` of the received tokens onwards to `receiver_id`.
+    /// The incoming transfer is finalised or rolled back together with this follow-up transfer.
+    Forward { receiver_id: AccountId, amount: U128, message: Vec<u8> },
+}
+
+/// JSON arguments of on_token_received
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct OnTokenReceivedArgs {
+    pub sender_id: AccountId,
+    pub amount_received: U128,
+    pub amount_total: U128,
+    pub message: Vec<u8>,
+}
+
+/// JSON arguments of ft_on_transfer
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct FtOnTransferArgs {
+    pub sender_id: AccountId,
+    pub amount: U128,
+    pub msg: String,
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_receiver_result_json() {
+        let rejected = ReceiverResult::Rejected { code: REJECT_CODE_CUSTOM, reason: String::from("Closed") };
+        assert_eq!(serde_json::to_string(&rejected).unwrap(), r#"{"Rejected":{"code":1000,"reason":"Closed"}}"#);
+        assert_eq!(serde_json::to_string(&ReceiverResult::Accepted).unwrap(), r#""Accepted""#);
+
+        let partial: ReceiverResult = serde_json::from_str(r#"{"AcceptedPartial":{"amount_used":"40"}}"#).unwrap();
+        assert_eq!(partial, ReceiverResult::AcceptedPartial { amount_used: 40.into() });
+
+        // Amounts are strings, not numbers
+        assert!(serde_json::from_str::<ReceiverResult>(r#"{"AcceptedPartial":{"amount_used":40}}"#).is_err());
+    }
+
+    #[test]
+    fn test_on_token_received_args_json() {
+        let args = OnTokenReceivedArgs {
+            sender_id: "bob.near".to_string(),
+            amount_received: 100.into(),
+            amount_total: 150.into(),
+            message: vec![1, 2],
+        };
+        assert_eq!(
+            serde_json::to_string(&args).unwrap(),
+            r#"{"sender_id":"bob.near","amount_received":"100","amount_total":"150","message":[1,2]}"#
+        );
+    }
+}
@@ -0,0 +1,60 @@
+/*
+ * The near-sdk side of the interface, behind the `sdk` feature.
+ *
+ * Receiver contracts implement the traits with #[near_bindgen] and the token calls them
+ * with the ext_contract helpers. Amounts are near_sdk::json_types::U128, which has
+ * the same JSON format as the U128 of the wire types.
+ */
+
+use near_sdk::json_types::U128;
+use near_sdk::{ ext_contract, PromiseOrValue };
+
+use crate::{ AccountId, ReceiverResult };
+
+impl From<U128> for crate::U128 {
+    fn from(value: U128) -> Self {
+        return crate::U128(value.0);
+    }
+}
+
+impl From<crate::U128> for U128 {
+    fn from(value: crate::U128) -> Self {
+        return U128(value.0);
+    }
+}
+
+/// The smart contract interface for handling incoming token transfers of Advanced Fungible
+pub trait Receiver {
+
+    /// Interface check, always returns true.
+    /// Does not need the contract state, so it is cheap to call before the contract is initialised.
+    fn is_receiver() -> bool;
+
+    /// Notified after the balance transfer is complete. The returned result tells
+    /// whether the transaction is finalised or rolled back.
+    /// A receiver that needs only part of `amount_received` returns AcceptedPartial
+    /// and the token contract refunds the rest to `sender_id`.
+    /// `amount_total` is the balance of the receiver including this transfer.
+    fn on_token_received(&mut self, sender_id: AccountId, amount_received: U128, amount_total: U128, message: Vec<u8>) -> PromiseOrValue<ReceiverResult>;
+}
+
+/// NEP-141 interface for smart contracts receiving tokens through ft_transfer_call()
+pub trait FungibleTokenReceiver {
+
+    /// Returns how many of the received tokens were not used and must be refunded to the sender
+    fn ft_on_transfer(&mut self, sender_id: AccountId, amount: U128, msg: String) -> PromiseOrValue<U128>;
+}
+
+// ext_contract replaces the trait with promise helpers, so the traits are repeated here for callers
+
+#[ext_contract(ext_token_receiver)]
+trait ReceiverCalls {
+    fn is_receiver(&self) -> bool;
+    fn on_token_received(&mut self, sender_id: AccountId, amount_received: U128, amount_total: U128, message: Vec<u8>) -> PromiseOrValue<ReceiverResult>;
+}
+
+#[ext_contract(ext_ft_receiver)]
+trait FungibleTokenReceiverCalls {
+    fn ft_on_transfer(&mut self, sender_id: AccountId, amount: U128, msg: String) -> PromiseOrValue<U128>;
+}
+
@@ -4,20 +4,34 @@
 
 use near_sdk::json_types::U128;
 use near_sdk::borsh::{ self, BorshDeserialize, BorshSerialize};
-use near_sdk::{ env, near_bindgen, AccountId, Balance, Promise };
+use near_sdk::serde::{ Serialize, Deserialize };
+use near_sdk::serde_json::json;
+use near_sdk::{ env, near_bindgen, AccountId, Balance, Promise, PromiseOrValue, PromiseResult };
 
-// use nep9000_token::receiver::{ Receiver };
+use nep9000_interface::{ FungibleTokenReceiver, Receiver, ReceiverResult, REJECT_CODE_CUSTOM };
 
 // ##[global_allocator]
 // static ALLOC: wee_alloc::WeeAlloc = wee_alloc::WeeAlloc::INIT;
 
 
+// TODO: All gas stipends are more or less random - check througfully
+const BURN_GAS: u64 = 20000000000000;
+
+/// Deposit attached to burn() unless new() is given one, the default confirmation deposit of the token
+pub const DEFAULT_BURN_DEPOSIT: Balance = 1;
+
+/// Reject code when tokens arrive from a token contract this pool does not handle
+pub const REJECT_WRONG_TOKEN: u32 = REJECT_CODE_CUSTOM;
+
+/// Reject code when the pool has already received its capacity
+pub const REJECT_POOL_FULL: u32 = REJECT_CODE_CUSTOM + 1;
 
 /*
  * A simple smart contract that can receive token transfers.
  *
  * It's called burner pool, because it is one way pool, so
- * mostly useful for testing.
+ * mostly useful for testing. Received tokens can be burnt,
+ * reducing the total supply of the token.
  *
  */
 #[near_bindgen]
@@ -30,6 +44,18 @@ pub struct BurnerPool {
     // How many tokens the contract has received overall
     pub total_received: Balance,
 
+    // Maximum number of tokens the pool accepts, the excess is refunded to the sender
+    pub capacity: Option<Balance>,
+
+    // How many of the received tokens have been burnt
+    pub total_burned: Balance,
+
+    // How many tokens are being burnt in a pending promise
+    pub burning: Balance,
+
+    // Deposit attached to burn(), must match the confirmation deposit of the token
+    pub burn_deposit: Balance,
+
 }
 
 
@@ -46,32 +72,78 @@ impl Default for BurnerPool {
  *
  */
 #[near_bindgen]
-impl BurnerPool {
+impl Receiver for BurnerPool {
 
     // This is called by the token contract to identify us as a compatible receiver
-    pub fn is_receiver() -> bool {
+    fn is_receiver() -> bool {
         env::log(b"is_receover reached");
-        return true;
+        true
     }
 
-    pub fn on_token_received(&mut self, sender_id: AccountId, amount_received: U128, amount_total: U128, message: Vec<u8>) -> Option<String> {
+    fn on_token_received(&mut self, sender_id: AccountId, amount_received: U128, amount_total: U128, message: Vec<u8>) -> PromiseOrValue<ReceiverResult> {
+        PromiseOrValue::Value(self.accept(amount_received.into()))
+    }
+}
 
-        assert_eq!(
-            self.token_id,
-            env::predecessor_account_id(),
-            "Pool can only receive the named token {}, got notifier from {}",
-            self.token_id, env::predecessor_account_id()
-        );
-        let amount: u128 = amount_received.into();
-        let uint_amount_total = amount_total.into();
-        self.total_received += amount;
+#[near_bindgen]
+impl FungibleTokenReceiver for BurnerPool {
+
+    /// NEP-141 receiver for ft_transfer_call(). Returns the amount of tokens to refund.
+    fn ft_on_transfer(&mut self, sender_id: AccountId, amount: U128, msg: String) -> PromiseOrValue<U128> {
+        let amount: u128 = amount.into();
+
+        if self.token_id != env::predecessor_account_id() {
+            env::log(format!("Pool can only receive the named token {}, got notifier from {}", self.token_id, env::predecessor_account_id()).as_bytes());
+            return PromiseOrValue::Value(amount.into());
+        }
+
+        let amount_used = self.receive(amount);
+        PromiseOrValue::Value((amount - amount_used).into())
+    }
+}
+
+impl BurnerPool {
+
+    /// Decide what to do with tokens sent with send().
+    /// Only the pool's own counters are used: the balance the token reports can include
+    /// tokens that arrived without a notification, like ft_transfer() or mint(), or tokens being burnt.
+    fn accept(&mut self, amount: Balance) -> ReceiverResult {
+
+        if self.token_id != env::predecessor_account_id() {
+            return ReceiverResult::Rejected {
+                code: REJECT_WRONG_TOKEN,
+                reason: format!("Pool can only receive the named token {}, got notifier from {}", self.token_id, env::predecessor_account_id()),
+            };
+        }
+
+        let amount_used = self.receive(amount);
+
+        if amount_used == 0 {
+            return ReceiverResult::Rejected {
+                code: REJECT_POOL_FULL,
+                reason: String::from("Pool is full"),
+            };
+        }
+
+        if amount_used < amount {
+            return ReceiverResult::AcceptedPartial { amount_used: amount_used.into() };
+        }
 
-        env::log(format!("on_token_received, incoming balance {} total {}", amount, self.total_received).as_bytes());
+        ReceiverResult::Accepted
+    }
+
+    /// Account incoming tokens. Returns how many we take, as we only take what fits in the pool.
+    fn receive(&mut self, amount: Balance) -> Balance {
+        let amount_used = match self.capacity {
+            Some(capacity) => std::cmp::min(amount, capacity - self.total_received),
+            None => amount,
+        };
+
+        self.total_received += amount_used;
 
-        assert!(self.total_received == uint_amount_total, "Mismatch between token ledger and pool balances");
+        env::log(format!("Incoming balance {} used {} total {}", amount, amount_used, self.total_received).as_bytes());
 
-        // TODO: Add error codes and graceful error handling
-        return None;
+        amount_used
     }
 
 }
@@ -79,26 +151,162 @@ impl BurnerPool {
 #[near_bindgen]
 impl BurnerPool {
 
+    /// `capacity` optionally limits how many tokens the pool accepts in total.
+    /// `burn_deposit` must match the confirmation deposit of the token, see get_confirmation_deposit().
     #[init]
-    pub fn new(token_id: AccountId) -> Self {
+    pub fn new(token_id: AccountId, capacity: Option<Balance>, burn_deposit: Option<Balance>) -> Self {
 
         assert!(!env::state_exists(), "Already initialized");
 
         assert!(
             env::is_valid_account_id(token_id.as_bytes()),
-            format!("{} account ID is invalid", token_id)
+            "{} account ID is invalid", token_id
         );
 
-        let pool = Self {
-            token_id: token_id,
+        Self {
+            token_id,
             total_received: 0,
-        };
-
-        return pool;
+            capacity,
+            total_burned: 0,
+            burning: 0,
+            burn_deposit: burn_deposit.unwrap_or(DEFAULT_BURN_DEPOSIT),
+        }
     }
 
     pub fn get_total_received(self) -> Balance {
-        return self.total_received;
+        self.total_received
+    }
+
+    pub fn get_total_burned(self) -> Balance {
+        self.total_burned
+    }
+
+    /**
+     * Burn all received tokens that have not been burnt yet.
+     *
+     * Anyone can call this. The token contract destroys the tokens from the pool's balance.
+     * Tokens of a transfer that is still pending are locked on the token side,
+     * so the burn fails until the transfer has been finalised.
+     */
+    pub fn burn_received(&mut self) {
+        let amount = self.total_received - self.total_burned - self.burning;
+        assert!(amount > 0, "Nothing to burn");
+
+        self.burning += amount;
+
+        let promise0 = env::promise_create(
+            self.token_id.clone(),
+            b"burn",
+            // Balance arguments are JSON numbers, which json!() cannot hold for u128
+            format!("{{\"amount\": {}}}", amount).as_bytes(),
+            self.burn_deposit,
+            BURN_GAS,
+        );
+
+        let promise1 = env::promise_then(
+            promise0,
+            env::current_account_id(),
+            b"handle_burn",
+            json!({
+                "amount": U128::from(amount),
+            }).to_string().as_bytes(),
+            0,
+            BURN_GAS,
+        );
+
+        env::promise_return(promise1);
+    }
+
+    /// Token contract has processed our burn
+    pub fn handle_burn(&mut self, amount: U128) {
+        // Only callable by self
+        assert_eq!(env::current_account_id(), env::predecessor_account_id());
+
+        let amount: u128 = amount.into();
+        self.burning -= amount;
+
+        match env::promise_result(0) {
+            PromiseResult::Successful(_) => {
+                self.total_burned += amount;
+                env::log(format!("Burnt {} tokens, total burned {}", amount, self.total_burned).as_bytes());
+            },
+            _ => {
+                env::log(format!("Burning {} tokens failed", amount).as_bytes());
+            },
+        }
     }
 }
 
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_sdk::PromiseResult;
+    use nep9000_testing::{ alice, bob, assert_logged, get_context, set_callback_context, set_context, unwrap_value };
+
+    fn token() -> AccountId {
+        "token.near".to_string()
+    }
+
+    #[test]
+    fn test_accepts_up_to_capacity() {
+        set_context(get_context(token()));
+        let mut pool = BurnerPool::new(token(), Some(100), None);
+
+        match unwrap_value(pool.on_token_received(bob(), 60.into(), 60.into(), vec![])) {
+            ReceiverResult::Accepted => (),
+            _ => panic!("Expected to accept all"),
+        }
+
+        match unwrap_value(pool.on_token_received(bob(), 60.into(), 120.into(), vec![])) {
+            ReceiverResult::AcceptedPartial { amount_used } => assert_eq!(amount_used.0, 40),
+            _ => panic!("Expected to accept partially"),
+        }
+
+        match unwrap_value(pool.on_token_received(bob(), 10.into(), 110.into(), vec![])) {
+            ReceiverResult::Rejected { code, .. } => assert_eq!(code, REJECT_POOL_FULL),
+            _ => panic!("Expected to reject"),
+        }
+
+        assert_eq!(pool.total_received, 100);
+    }
+
+    #[test]
+    fn test_ft_on_transfer_refunds_over_capacity() {
+        set_context(get_context(token()));
+        let mut pool = BurnerPool::new(token(), Some(100), None);
+        assert_eq!(unwrap_value(pool.ft_on_transfer(bob(), 150.into(), String::from(""))), 50.into());
+        assert_eq!(pool.total_received, 100);
+
+        // Other tokens are refunded in full
+        set_context(get_context(bob()));
+        assert_eq!(unwrap_value(pool.ft_on_transfer(bob(), 10.into(), String::from(""))), 10.into());
+    }
+
+    #[test]
+    fn test_burn_received() {
+        set_context(get_context(token()));
+        let mut pool = BurnerPool::new(token(), None, None);
+        pool.on_token_received(bob(), 60.into(), 60.into(), vec![]);
+
+        set_context(get_context(bob()));
+        pool.burn_received();
+        assert_eq!(pool.burning, 60);
+
+        // A failed burn can be retried
+        set_callback_context(get_context(alice()), PromiseResult::Failed);
+        pool.handle_burn(60.into());
+        assert_eq!(pool.burning, 0);
+        assert_eq!(pool.total_burned, 0);
+        assert_logged("Burning 60 tokens failed");
+
+        set_context(get_context(bob()));
+        pool.burn_received();
+        set_callback_context(get_context(alice()), PromiseResult::Successful(vec![]));
+        pool.handle_burn(60.into());
+        assert_eq!(pool.burning, 0);
+        assert_eq!(pool.total_burned, 60);
+        assert_logged("Burnt 60 tokens, total burned 60");
+    }
+}
@@ -0,0 +1,417 @@
+/*
+ * Handlers for the contracts of this repository.
+ *
+ * List a method here before calling it through the simulator.
+ * View methods can also be called directly on the state with Simulator::view().
+ */
+
+use near_sdk::json_types::U128;
+use near_sdk::{ AccountId, Balance, BlockHeight };
+
+use nep9000_pool::BurnerPool;
+use nep9000_token::metadata::Metadata;
+use nep9000_interface::{ FungibleTokenReceiver, Receiver };
+use nep9000_token::token::{ Token, TransferId };
+
+use crate::handler;
+use crate::simulator::Handler;
+
+pub fn token() -> Handler {
+    return handler!(Token {
+        init new(owner_id: AccountId, total_supply: Balance, metadata: Option<Metadata>, max_supply: Option<Balance>, lock_expiry_blocks: Option<BlockHeight>);
+
+        call send(new_owner_id: AccountId, amount: Balance, message: Vec<u8>);
+        call send_batch(transfers: Vec<(AccountId, U128, Vec<u8>)>);
+        call pay_batch(transfers: Vec<(AccountId, U128)>);
+        call handle_receiver(transfer_id: TransferId, amount_total: U128, message: Vec<u8>);
+        call handle_token_received(transfer_id: TransferId);
+        call reclaim_expired(transfer_id: TransferId);
+        call update_metadata(metadata: Metadata);
+        call set_confirmation_deposit(amount: U128);
+        call add_minter(account_id: AccountId);
+        call remove_minter(account_id: AccountId);
+        call mint(account_id: AccountId, amount: Balance);
+        call burn(amount: Balance);
+        call burn_from(account_id: AccountId, amount: Balance);
+        call delegate(delegate_id: AccountId);
+
+        call ft_transfer(receiver_id: AccountId, amount: U128, memo: Option<String>);
+        call ft_transfer_call(receiver_id: AccountId, amount: U128, memo: Option<String>, msg: String);
+        call ft_resolve_transfer(sender_id: AccountId, receiver_id: AccountId, amount: U128, transfer_id: TransferId);
+
+        call inc_allowance(escrow_account_id: AccountId, amount: U128);
+        call dec_allowance(escrow_account_id: AccountId, amount: U128);
+        call transfer_from(owner_id: AccountId, new_owner_id: AccountId, amount: U128);
+
+        call storage_deposit(account_id: Option<AccountId>, registration_only: Option<bool>);
+        call storage_withdraw(amount: Option<U128>);
+        call storage_unregister(force: Option<bool>);
+
+        view get_balance(owner_id: AccountId);
+        view get_locked_balance(owner_id: AccountId);
+        view get_total_supply();
+        view get_transfer(transfer_id: TransferId);
+        view ft_balance_of(account_id: AccountId);
+        view ft_total_supply();
+        view storage_balance_of(account_id: AccountId);
+        view storage_balance_bounds();
+    });
+}
+
+pub fn burner_pool() -> Handler {
+    return handler!(BurnerPool {
+        init new(token_id: AccountId, capacity: Option<Balance>, burn_deposit: Option<Balance>);
+
+        stateless is_receiver();
+        call on_token_received(sender_id: AccountId, amount_received: U128, amount_total: U128, message: Vec<u8>);
+        call ft_on_transfer(sender_id: AccountId, amount: U128, msg: String);
+        call burn_received();
+        call handle_burn(amount: U128);
+
+        view get_total_received();
+        view get_total_burned();
+    });
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_sdk::serde_json::{ self, json };
+
+    use nep9000_token::receiver::{ ReceiverResult, REJECT_CODE_CUSTOM };
+    use nep9000_token::token::TransferStatus;
+
+    use crate::simulator::{ Fault, Simulator };
+
+    const TOKEN: &str = "token.near";
+
+    const POOL: &str = "pool.near";
+
+    const POOL2: &str = "pool2.near";
+
+    const BOB: &str = "bob.near";
+
+    const CAROL: &str = "carol.near";
+
+    const ROUTER: &str = "router.near";
+
+    const TOTAL_SUPPLY: Balance = 1_000_000;
+
+    /// Storage stake of the accounts that send tokens, 1 NEAR
+    const STAKE: Balance = 1_000_000_000_000_000_000_000_000;
+
+    /// Token owned by bob, with the pool and carol registered
+    fn setup(capacity: Option<Balance>) -> Simulator {
+        let mut sim = Simulator::new();
+        sim.deploy(TOKEN, token());
+        sim.deploy(POOL, burner_pool());
+        sim.call(BOB, TOKEN, "new", &format!("{{\"owner_id\": \"{}\", \"total_supply\": {}, \"lock_expiry_blocks\": 10}}", BOB, TOTAL_SUPPLY), 0).unwrap();
+        let capacity = match capacity {
+            Some(x) => x.to_string(),
+            None => String::from("null"),
+        };
+        sim.call(BOB, POOL, "new", &format!("{{\"token_id\": \"{}\", \"capacity\": {}}}", TOKEN, capacity), 0).unwrap();
+
+        let cost = sim.view(TOKEN, |t: &Token| t.storage_balance_bounds().min.0);
+        for account_id in &[POOL, CAROL] {
+            sim.call(account_id, TOKEN, "storage_deposit", "{}", cost).unwrap();
+        }
+        // Senders pay for the balance history they write
+        for account_id in &[BOB, POOL] {
+            sim.call(account_id, TOKEN, "storage_deposit", "{}", STAKE).unwrap();
+        }
+        return sim;
+    }
+
+    fn send(sim: &mut Simulator, receiver_id: &str, amount: Balance) -> TransferId {
+        let args = format!("{{\"new_owner_id\": \"{}\", \"amount\": {}, \"message\": []}}", receiver_id, amount);
+        return sim.call_json(BOB, TOKEN, "send", &args, 1).unwrap();
+    }
+
+    fn get_status(sim: &mut Simulator, transfer_id: TransferId) -> TransferStatus {
+        return sim.view(TOKEN, |t: &Token| t.get_transfer(transfer_id).unwrap().status);
+    }
+
+    /// Deploy a receiver at `account_id` that answers every transfer with `result`, and register it with `deposit`
+    fn deploy_receiver(sim: &mut Simulator, account_id: &str, result: &ReceiverResult, deposit: Balance) {
+        let result = serde_json::to_vec(result).unwrap();
+        sim.deploy(account_id, Box::new(move |method: &str, _input: &[u8]| -> Vec<u8> {
+            match method {
+                "is_receiver" => b"true".to_vec(),
+                "on_token_received" => result.clone(),
+                _ => panic!("Unexpected call {}", method),
+            }
+        }));
+        sim.call(account_id, TOKEN, "storage_deposit", "{}", deposit).unwrap();
+    }
+
+    /// Deploy a receiver at router.near that forwards 70 tokens of every transfer to the pool
+    fn deploy_router(sim: &mut Simulator) {
+        let forward = ReceiverResult::Forward { receiver_id: POOL.to_string(), amount: 70.into(), message: vec![1] };
+        // The router pays for the follow-up transfer
+        let cost = sim.view(TOKEN, |t: &Token| t.storage_balance_bounds().min.0);
+        deploy_receiver(sim, ROUTER, &forward, cost + STAKE);
+    }
+
+    #[test]
+    fn test_send_to_pool() {
+        let mut sim = setup(None);
+        let transfer_id = send(&mut sim, POOL, 100);
+
+        assert_eq!(get_status(&mut sim, transfer_id), TransferStatus::Finalised);
+        assert_eq!(sim.view(TOKEN, |t: &Token| t.get_balance(POOL.to_string())), 100);
+        assert_eq!(sim.view(TOKEN, |t: &Token| t.get_locked_balance(POOL.to_string())), 0);
+        assert_eq!(sim.view(POOL, |p: &BurnerPool| p.total_received), 100);
+
+        let methods: Vec<String> = sim.outcomes().iter().skip(6).map(|o| match &o.receipt.actions[0] {
+            crate::simulator::Action::FunctionCall { method_name, .. } => method_name.clone(),
+            _ => String::new(),
+        }).collect();
+        assert_eq!(methods, vec!["send", "is_receiver", "handle_receiver", "on_token_received", "handle_token_received"]);
+    }
+
+    #[test]
+    fn test_send_to_account_without_contract() {
+        let mut sim = setup(None);
+        let transfer_id = send(&mut sim, CAROL, 100);
+        assert_eq!(get_status(&mut sim, transfer_id), TransferStatus::Finalised);
+        assert_eq!(sim.view(TOKEN, |t: &Token| t.get_balance(CAROL.to_string())), 100);
+    }
+
+    #[test]
+    fn test_send_batch_notifies_every_recipient() {
+        let mut sim = setup(Some(60));
+        let args = json!({ "transfers": [[POOL, "100", []], [CAROL, "50", []]] }).to_string();
+        let transfer_ids: Vec<TransferId> = sim.call_json(BOB, TOKEN, "send_batch", &args, 1).unwrap();
+
+        // The pool is told about its tokens even without a message, and refunds what is over its capacity
+        assert_eq!(get_status(&mut sim, transfer_ids[0]), TransferStatus::Finalised);
+        assert_eq!(get_status(&mut sim, transfer_ids[1]), TransferStatus::Finalised);
+        assert_eq!(sim.view(POOL, |p: &BurnerPool| p.total_received), 60);
+        assert_eq!(sim.view(TOKEN, |t: &Token| t.get_balance(POOL.to_string())), 60);
+        assert_eq!(sim.view(TOKEN, |t: &Token| t.get_balance(CAROL.to_string())), 50);
+        assert_eq!(sim.view(TOKEN, |t: &Token| t.get_balance(BOB.to_string())), TOTAL_SUPPLY - 110);
+    }
+
+    #[test]
+    fn test_send_batch_rolls_back_failed_recipient() {
+        let mut sim = setup(None);
+        deploy_router(&mut sim);
+        sim.inject(POOL, "on_token_received", Fault::Panic);
+        let args = json!({ "transfers": [[POOL, "100", []], [CAROL, "50", []], [ROUTER, "100", []]] }).to_string();
+        let transfer_ids: Vec<TransferId> = sim.call_json(BOB, TOKEN, "send_batch", &args, 1).unwrap();
+
+        // Each failure rolls back only its own transfer
+        assert_eq!(get_status(&mut sim, transfer_ids[0]), TransferStatus::RolledBack);
+        assert_eq!(get_status(&mut sim, transfer_ids[1]), TransferStatus::Finalised);
+        assert_eq!(sim.view(TOKEN, |t: &Token| t.get_balance(CAROL.to_string())), 50);
+        assert_eq!(sim.view(TOKEN, |t: &Token| t.get_balance(BOB.to_string())), TOTAL_SUPPLY - 50);
+
+        // A batch recipient does not get enough gas to forward
+        assert_eq!(get_status(&mut sim, transfer_ids[2]), TransferStatus::RolledBack);
+        assert!(sim.logs().iter().any(|line| line.contains("Not enough gas to forward tokens")));
+        assert_eq!(sim.view(TOKEN, |t: &Token| t.get_balance(ROUTER.to_string())), 0);
+    }
+
+    #[test]
+    fn test_pay_batch_does_not_notify() {
+        let mut sim = setup(None);
+        let args = json!({ "transfers": [[POOL, "100"], [CAROL, "50"]] }).to_string();
+        let transfer_ids: Vec<TransferId> = sim.call_json(BOB, TOKEN, "pay_batch", &args, 1).unwrap();
+
+        assert_eq!(get_status(&mut sim, transfer_ids[0]), TransferStatus::Finalised);
+        assert_eq!(sim.view(TOKEN, |t: &Token| t.get_balance(POOL.to_string())), 100);
+        assert_eq!(sim.view(TOKEN, |t: &Token| t.get_balance(CAROL.to_string())), 50);
+        assert_eq!(sim.view(POOL, |p: &BurnerPool| p.total_received), 0);
+    }
+
+    #[test]
+    fn test_pool_accepts_partially() {
+        let mut sim = setup(Some(60));
+        let transfer_id = send(&mut sim, POOL, 100);
+
+        assert_eq!(get_status(&mut sim, transfer_id), TransferStatus::Finalised);
+        assert_eq!(sim.view(TOKEN, |t: &Token| t.get_balance(POOL.to_string())), 60);
+        assert_eq!(sim.view(TOKEN, |t: &Token| t.get_balance(BOB.to_string())), TOTAL_SUPPLY - 60);
+    }
+
+    #[test]
+    fn test_receiver_panic_rolls_back() {
+        let mut sim = setup(None);
+        sim.inject(POOL, "on_token_received", Fault::Panic);
+        let transfer_id = send(&mut sim, POOL, 100);
+
+        assert_eq!(get_status(&mut sim, transfer_id), TransferStatus::RolledBack);
+        assert_eq!(sim.view(TOKEN, |t: &Token| t.get_balance(BOB.to_string())), TOTAL_SUPPLY);
+        assert_eq!(sim.view(TOKEN, |t: &Token| t.get_rollback_count()), 1);
+        assert_eq!(sim.view(POOL, |p: &BurnerPool| p.total_received), 0);
+    }
+
+    #[test]
+    fn test_callback_out_of_gas_leaves_transfer_locked() {
+        let mut sim = setup(None);
+        sim.inject(TOKEN, "handle_token_received", Fault::OutOfGas);
+        let transfer_id = send(&mut sim, POOL, 100);
+
+        // The pool has accounted the tokens, but the token contract never heard back
+        assert_eq!(get_status(&mut sim, transfer_id), TransferStatus::Pending);
+        assert_eq!(sim.view(TOKEN, |t: &Token| t.get_locked_balance(POOL.to_string())), 100);
+
+        let args = json!({ "transfer_id": transfer_id }).to_string();
+        assert!(sim.call(BOB, TOKEN, "reclaim_expired", &args, 0).unwrap_err().contains("is locked until block"));
+
+        sim.skip_blocks(10);
+        sim.call(BOB, TOKEN, "reclaim_expired", &args, 0).unwrap();
+        assert_eq!(get_status(&mut sim, transfer_id), TransferStatus::RolledBack);
+        assert_eq!(sim.view(TOKEN, |t: &Token| t.get_balance(BOB.to_string())), TOTAL_SUPPLY);
+    }
+
+    #[test]
+    fn test_custom_receiver_rejects() {
+        let mut sim = setup(None);
+        let rejected = ReceiverResult::Rejected { code: REJECT_CODE_CUSTOM, reason: String::from("Closed") };
+        let cost = sim.view(TOKEN, |t: &Token| t.storage_balance_bounds().min.0);
+        deploy_receiver(&mut sim, "shop.near", &rejected, cost);
+
+        let transfer_id = send(&mut sim, "shop.near", 100);
+        let transfer = sim.view(TOKEN, |t: &Token| t.get_transfer(transfer_id).unwrap());
+        assert_eq!(transfer.status, TransferStatus::RolledBack);
+        assert_eq!(transfer.result, Some(rejected));
+        assert_eq!(sim.view(TOKEN, |t: &Token| t.get_balance(BOB.to_string())), TOTAL_SUPPLY);
+        assert!(sim.logs().iter().any(|line| line.contains("Receiver rejected transfer 0 with code 1000: Closed")));
+    }
+
+    #[test]
+    fn test_ft_transfer_call_returns_used_amount() {
+        let mut sim = setup(Some(60));
+        let args = json!({ "receiver_id": POOL, "amount": "100", "msg": "" }).to_string();
+        let used: U128 = sim.call_json(BOB, TOKEN, "ft_transfer_call", &args, 1).unwrap();
+        assert_eq!(used.0, 60);
+        assert_eq!(sim.view(TOKEN, |t: &Token| t.get_balance(BOB.to_string())), TOTAL_SUPPLY - 60);
+    }
+
+    #[test]
+    fn test_burn_received() {
+        let mut sim = setup(None);
+        send(&mut sim, POOL, 100);
+
+        // A failed burn can be retried
+        sim.inject(TOKEN, "burn", Fault::Panic);
+        sim.call(CAROL, POOL, "burn_received", "{}", 0).unwrap();
+        assert_eq!(sim.view(POOL, |p: &BurnerPool| p.total_burned), 0);
+        assert_eq!(sim.view(TOKEN, |t: &Token| t.get_total_supply()), TOTAL_SUPPLY);
+
+        sim.call(CAROL, POOL, "burn_received", "{}", 0).unwrap();
+        assert_eq!(sim.view(POOL, |p: &BurnerPool| p.total_burned), 100);
+        assert_eq!(sim.view(TOKEN, |t: &Token| t.get_total_supply()), TOTAL_SUPPLY - 100);
+        assert_eq!(sim.view(TOKEN, |t: &Token| t.get_balance(POOL.to_string())), 0);
+    }
+
+    #[test]
+    fn test_burn_with_changed_confirmation_deposit() {
+        let mut sim = setup(None);
+        sim.call(BOB, TOKEN, "set_confirmation_deposit", "{\"amount\": \"5\"}", 1).unwrap();
+        let args = format!("{{\"new_owner_id\": \"{}\", \"amount\": 100, \"message\": []}}", POOL);
+        sim.call(BOB, TOKEN, "send", &args, 5).unwrap();
+
+        // The pool still attaches the default deposit, so the token refuses the burn
+        sim.call(CAROL, POOL, "burn_received", "{}", 0).unwrap();
+        assert_eq!(sim.view(POOL, |p: &BurnerPool| p.total_burned), 0);
+
+        sim.deploy(POOL2, burner_pool());
+        sim.call(BOB, POOL2, "new", &format!("{{\"token_id\": \"{}\", \"burn_deposit\": 5}}", TOKEN), 0).unwrap();
+        let cost = sim.view(TOKEN, |t: &Token| t.storage_balance_bounds().min.0);
+        sim.call(POOL2, TOKEN, "storage_deposit", "{}", cost + STAKE).unwrap();
+        let args = format!("{{\"new_owner_id\": \"{}\", \"amount\": 100, \"message\": []}}", POOL2);
+        sim.call(BOB, TOKEN, "send", &args, 5).unwrap();
+
+        sim.call(CAROL, POOL2, "burn_received", "{}", 0).unwrap();
+        assert_eq!(sim.view(POOL2, |p: &BurnerPool| p.total_burned), 100);
+        assert_eq!(sim.view(TOKEN, |t: &Token| t.get_total_supply()), TOTAL_SUPPLY - 100);
+    }
+
+    #[test]
+    fn test_send_to_pool_after_burn() {
+        let mut sim = setup(None);
+        send(&mut sim, POOL, 100);
+        sim.call(CAROL, POOL, "burn_received", "{}", 0).unwrap();
+
+        let transfer_id = send(&mut sim, POOL, 50);
+        assert_eq!(get_status(&mut sim, transfer_id), TransferStatus::Finalised);
+        assert_eq!(sim.view(TOKEN, |t: &Token| t.get_balance(POOL.to_string())), 50);
+        assert_eq!(sim.view(POOL, |p: &BurnerPool| p.total_received), 150);
+    }
+
+    #[test]
+    fn test_send_to_pool_after_unnotified_credit() {
+        let mut sim = setup(None);
+        let args = json!({ "receiver_id": POOL, "amount": "40" }).to_string();
+        sim.call(BOB, TOKEN, "ft_transfer", &args, 1).unwrap();
+
+        // The pool never heard of the 40 tokens, but still accepts the next send()
+        let transfer_id = send(&mut sim, POOL, 100);
+        assert_eq!(get_status(&mut sim, transfer_id), TransferStatus::Finalised);
+        assert_eq!(sim.view(TOKEN, |t: &Token| t.get_balance(POOL.to_string())), 140);
+        assert_eq!(sim.view(POOL, |p: &BurnerPool| p.total_received), 100);
+    }
+
+    #[test]
+    fn test_pool_refunds_from_own_counters_after_unnotified_credit() {
+        let mut sim = setup(Some(60));
+        let args = json!({ "receiver_id": POOL, "amount": "40" }).to_string();
+        sim.call(BOB, TOKEN, "ft_transfer", &args, 1).unwrap();
+
+        // The capacity counts only what the pool was told about, the unnotified 40 tokens stay with it
+        let transfer_id = send(&mut sim, POOL, 100);
+        assert_eq!(get_status(&mut sim, transfer_id), TransferStatus::Finalised);
+        assert_eq!(sim.view(TOKEN, |t: &Token| t.get_transfer(transfer_id).unwrap().refunded), 40.into());
+        assert_eq!(sim.view(TOKEN, |t: &Token| t.get_balance(POOL.to_string())), 100);
+        assert_eq!(sim.view(POOL, |p: &BurnerPool| p.total_received), 60);
+        assert_eq!(sim.view(TOKEN, |t: &Token| t.get_balance(BOB.to_string())), TOTAL_SUPPLY - 100);
+    }
+
+    #[test]
+    fn test_forward_to_pool() {
+        let mut sim = setup(None);
+        deploy_router(&mut sim);
+
+        let transfer_id = send(&mut sim, ROUTER, 100);
+        assert_eq!(get_status(&mut sim, transfer_id), TransferStatus::Finalised);
+        assert_eq!(get_status(&mut sim, transfer_id + 1), TransferStatus::Finalised);
+        assert_eq!(sim.view(TOKEN, |t: &Token| t.get_transfer(transfer_id + 1).unwrap().parent_id), Some(transfer_id));
+        assert_eq!(sim.view(TOKEN, |t: &Token| t.get_balance(ROUTER.to_string())), 30);
+        assert_eq!(sim.view(TOKEN, |t: &Token| t.get_locked_balance(ROUTER.to_string())), 0);
+        assert_eq!(sim.view(TOKEN, |t: &Token| t.get_balance(POOL.to_string())), 70);
+        assert_eq!(sim.view(POOL, |p: &BurnerPool| p.total_received), 70);
+    }
+
+    #[test]
+    fn test_failed_forward_rolls_back_whole_chain() {
+        let mut sim = setup(None);
+        deploy_router(&mut sim);
+        sim.inject(POOL, "on_token_received", Fault::Panic);
+
+        let transfer_id = send(&mut sim, ROUTER, 100);
+        assert_eq!(get_status(&mut sim, transfer_id + 1), TransferStatus::RolledBack);
+        assert_eq!(get_status(&mut sim, transfer_id), TransferStatus::RolledBack);
+        assert_eq!(sim.view(TOKEN, |t: &Token| t.get_balance(BOB.to_string())), TOTAL_SUPPLY);
+        assert_eq!(sim.view(TOKEN, |t: &Token| t.get_balance(ROUTER.to_string())), 0);
+        assert_eq!(sim.view(TOKEN, |t: &Token| t.get_locked_balance(ROUTER.to_string())), 0);
+        assert_eq!(sim.view(TOKEN, |t: &Token| t.get_balance(POOL.to_string())), 0);
+    }
+
+    #[test]
+    fn test_forward_without_storage_deposit_rolls_back() {
+        let mut sim = setup(None);
+        let forward = ReceiverResult::Forward { receiver_id: POOL.to_string(), amount: 70.into(), message: vec![1] };
+        let cost = sim.view(TOKEN, |t: &Token| t.storage_balance_bounds().min.0);
+        deploy_receiver(&mut sim, ROUTER, &forward, cost);
+
+        let transfer_id = send(&mut sim, ROUTER, 100);
+        assert_eq!(get_status(&mut sim, transfer_id), TransferStatus::RolledBack);
+        assert_eq!(sim.view(TOKEN, |t: &Token| t.get_balance(BOB.to_string())), TOTAL_SUPPLY);
+        assert_eq!(sim.view(POOL, |p: &BurnerPool| p.total_received), 0);
+        assert!(sim.logs().iter().any(|line| line.contains("router.near needs a storage deposit to forward tokens")));
+    }
+}
@@ -0,0 +1,93 @@
+/*
+ * In-process simulator for testing contract interactions in Rust unit tests.
+ *
+ * Hosts the token, the burner pool and custom receivers in one mocked world
+ * and executes the promise chains between them, without a testnet:
+ *
+ *     let mut sim = Simulator::new();
+ *     sim.deploy("token.near", contracts::token());
+ *     sim.deploy("pool.near", contracts::burner_pool());
+ *     sim.call("bob.near", "token.near", "send", &args, 1).unwrap();
+ *
+ * Contracts are plain Rust, so the contract methods are reached through a Handler
+ * that maps method names to calls. handler! builds one for a near_bindgen contract.
+ */
+
+#![cfg(not(target_arch = "wasm32"))]
+
+#![allow(dead_code)]
+#![allow(unused_imports)]
+#![allow(unused_variables)]
+#![allow(clippy::needless_return)]
+#![allow(clippy::useless_conversion)]
+#![allow(clippy::redundant_field_names)]
+
+pub mod contracts;
+pub mod simulator;
+
+pub use crate::simulator::{ Action, Fault, Handler, Outcome, Receipt, ReceiptId, ReceiptStatus, Simulator, DEFAULT_BALANCE, DEFAULT_GAS };
+
+/// Decode the JSON arguments of a method, empty input meaning no arguments
+pub fn parse_args<T: near_sdk::serde::de::DeserializeOwned>(input: &[u8]) -> T {
+    let input = if input.is_empty() { b"{}" } else { input };
+    match near_sdk::serde_json::from_slice(input) {
+        Ok(args) => args,
+        Err(e) => near_sdk::env::panic(format!("Failed to deserialize input: {}", e).as_bytes()),
+    }
+}
+
+/**
+ * Build a Handler for a near_bindgen contract by listing its methods the way they are called:
+ *
+ * ```text
+ * handler!(BurnerPool {
+ *     init new(token_id: AccountId, capacity: Option<Balance>, burn_deposit: Option<Balance>);
+ *     call on_token_received(sender_id: AccountId, amount_received: U128, amount_total: U128, message: Vec<u8>);
+ *     view get_total_received();
+ *     stateless is_receiver();
+ * })
+ * ```
+ *
+ * `init` stores the returned contract, `call` writes the contract back after the call,
+ * `view` only reads it and `stateless` calls an associated function.
+ * Return values are serialised to JSON, so methods without one return `null`.
+ */
+#[macro_export]
+macro_rules! handler {
+    ($contract:ty { $($kind:ident $method:ident($($arg:ident: $ty:ty),* $(,)?);)* }) => {
+        Box::new(|method: &str, input: &[u8]| -> Vec<u8> {
+            $(
+                if method == stringify!($method) {
+                    #[derive(near_sdk::serde::Deserialize)]
+                    #[serde(crate = "near_sdk::serde")]
+                    struct Args {
+                        $($arg: $ty,)*
+                    }
+                    #[allow(unused_variables)]
+                    let Args { $($arg,)* } = $crate::parse_args::<Args>(input);
+                    return $crate::handler!(@$kind $contract, $method($($arg),*));
+                }
+            )*
+            near_sdk::env::panic(format!("Contract has no method {}", method).as_bytes())
+        }) as $crate::Handler
+    };
+    (@init $contract:ty, $method:ident($($arg:ident),*)) => {{
+        let contract = <$contract>::$method($($arg),*);
+        near_sdk::env::state_write(&contract);
+        vec![]
+    }};
+    (@call $contract:ty, $method:ident($($arg:ident),*)) => {{
+        let mut contract: $contract = near_sdk::env::state_read().expect("Contract is not initialized");
+        #[allow(clippy::let_unit_value)]
+        let result = contract.$method($($arg),*);
+        near_sdk::env::state_write(&contract);
+        near_sdk::serde_json::to_vec(&result).unwrap()
+    }};
+    (@view $contract:ty, $method:ident($($arg:ident),*)) => {{
+        let contract: $contract = near_sdk::env::state_read().expect("Contract is not initialized");
+        near_sdk::serde_json::to_vec(&contract.$method($($arg),*)).unwrap()
+    }};
+    (@stateless $contract:ty, $method:ident($($arg:ident),*)) => {{
+        near_sdk::serde_json::to_vec(&<$contract>::$method($($arg),*)).unwrap()
+    }};
+}
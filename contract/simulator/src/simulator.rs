@@ -0,0 +1,483 @@
+/*
+ * Runs several contracts in one mocked world.
+ *
+ * Each account has its own storage and NEAR balance. A call becomes a receipt,
+ * and the receipts a contract creates with promise_create() and promise_then()
+ * are queued and executed in order, one receipt per block.
+ * Callbacks get the results of the receipts they depend on as promise results.
+ *
+ * Failures can be injected into any hop with inject(), so tests can check
+ * how the token contract rolls back when a receiver panics or runs out of gas.
+ */
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::panic::{ self, AssertUnwindSafe };
+use std::rc::Rc;
+
+use near_sdk::borsh::BorshDeserialize;
+use near_sdk::serde::Deserialize;
+use near_sdk::serde_json;
+use near_sdk::{ env, AccountId, Balance, BlockHeight, Gas, MockedBlockchain, PromiseResult, StorageUsage, VMContext };
+
+use nep9000_testing::blockchain::{ CallRecord, SimulatedBlockchain };
+
+pub type ReceiptId = u64;
+
+/// Calls a contract method by its name with JSON arguments and returns the JSON result
+pub type Handler = Box<dyn Fn(&str, &[u8]) -> Vec<u8>>;
+
+/// Gas attached to calls made by tests, the maximum of a NEAR transaction
+pub const DEFAULT_GAS: Gas = 300_000_000_000_000;
+
+/// NEAR balance of new accounts, 1000 NEAR
+pub const DEFAULT_BALANCE: Balance = 1_000_000_000_000_000_000_000_000_000;
+
+/// Failure injected into a receipt instead of executing it
+#[derive(Clone, PartialEq, Debug)]
+pub enum Fault {
+    Panic,
+    OutOfGas,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum Action {
+    FunctionCall { method_name: String, args: Vec<u8>, deposit: Balance, gas: Gas },
+    Transfer { deposit: Balance },
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Receipt {
+    pub receipt_id: ReceiptId,
+    pub signer_id: AccountId,
+    pub predecessor_id: AccountId,
+    pub receiver_id: AccountId,
+    pub actions: Vec<Action>,
+
+    // Receipts whose results this receipt gets as promise results
+    pub depends_on: Vec<ReceiptId>,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum ReceiptStatus {
+    Success(Vec<u8>),
+    Failure(String),
+
+    // The receipt returned a promise, its result is the result of that receipt
+    Postponed(ReceiptId),
+}
+
+/// An executed receipt
+#[derive(Clone, Debug)]
+pub struct Outcome {
+    pub receipt: Receipt,
+    pub block_index: BlockHeight,
+    pub status: ReceiptStatus,
+    pub logs: Vec<String>,
+}
+
+struct Account {
+    balance: Balance,
+    storage: HashMap<Vec<u8>, Vec<u8>>,
+    storage_usage: StorageUsage,
+    handler: Option<Handler>,
+}
+
+impl Default for Account {
+    fn default() -> Self {
+        Self {
+            balance: DEFAULT_BALANCE,
+            storage: HashMap::new(),
+            storage_usage: 0,
+            handler: None,
+        }
+    }
+}
+
+// Mirror of the receipts near-vm-logic records, which are only exposed as serde types
+#[derive(Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+struct CreatedReceipt {
+    receipt_indices: Vec<u64>,
+    receiver_id: AccountId,
+    actions: Vec<CreatedAction>,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+enum CreatedAction {
+    FunctionCall { method_name: String, args: String, gas: Gas, deposit: Balance },
+    Transfer { deposit: Balance },
+}
+
+/// What a successful function call left behind
+struct CallOutput {
+    value: Vec<u8>,
+    receipts: Vec<CreatedReceipt>,
+    returned_promise: Option<u64>,
+}
+
+pub struct Simulator {
+
+    // Block of the last executed receipt
+    pub block_index: BlockHeight,
+
+    accounts: HashMap<AccountId, Account>,
+
+    // Receipts waiting for execution, in creation order
+    queue: Vec<Receipt>,
+
+    results: HashMap<ReceiptId, ReceiptStatus>,
+
+    faults: Vec<(AccountId, String, Fault)>,
+
+    outcomes: Vec<Outcome>,
+
+    next_receipt_id: ReceiptId,
+}
+
+impl Default for Simulator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Simulator {
+
+    pub fn new() -> Self {
+        Self {
+            block_index: 0,
+            accounts: HashMap::new(),
+            queue: vec![],
+            results: HashMap::new(),
+            faults: vec![],
+            outcomes: vec![],
+            next_receipt_id: 0,
+        }
+    }
+
+    /// Deploy a contract to `account_id`, creating the account if needed
+    pub fn deploy(&mut self, account_id: &str, handler: Handler) {
+        self.accounts.entry(account_id.to_string()).or_default().handler = Some(handler);
+    }
+
+    /// NEAR balance of `account_id`
+    pub fn get_balance(&self, account_id: &str) -> Balance {
+        match self.accounts.get(account_id) {
+            Some(account) => return account.balance,
+            None => return 0,
+        }
+    }
+
+    /// Fail the next call of `method_name` on `account_id` instead of executing it
+    pub fn inject(&mut self, account_id: &str, method_name: &str, fault: Fault) {
+        self.faults.push((account_id.to_string(), method_name.to_string(), fault));
+    }
+
+    pub fn skip_blocks(&mut self, blocks: BlockHeight) {
+        self.block_index += blocks;
+    }
+
+    /// All executed receipts in execution order
+    pub fn outcomes(&self) -> &Vec<Outcome> {
+        return &self.outcomes;
+    }
+
+    /// Log lines of all executed receipts in execution order
+    pub fn logs(&self) -> Vec<String> {
+        return self.outcomes.iter().flat_map(|o| o.logs.clone()).collect();
+    }
+
+    /**
+     * Call `method_name` of `receiver_id` as `signer_id` and run until no receipts are left.
+     *
+     * Returns the result of the call, following the promises it returned,
+     * or the panic message if the call failed.
+     * Promise chains the call started without returning them are executed as well.
+     */
+    pub fn call(&mut self, signer_id: &str, receiver_id: &str, method_name: &str, args: &str, deposit: Balance) -> Result<Vec<u8>, String> {
+        let receipt_id = self.submit(signer_id, receiver_id, method_name, args, deposit);
+        self.run();
+        return self.get_result(receipt_id).expect("Receipt was not executed");
+    }
+
+    /// Like call(), but decode the JSON result
+    pub fn call_json<T: near_sdk::serde::de::DeserializeOwned>(&mut self, signer_id: &str, receiver_id: &str, method_name: &str, args: &str, deposit: Balance) -> Result<T, String> {
+        let value = self.call(signer_id, receiver_id, method_name, args, deposit)?;
+        return Ok(serde_json::from_slice(&value).unwrap());
+    }
+
+    /// Queue a call without executing it
+    pub fn submit(&mut self, signer_id: &str, receiver_id: &str, method_name: &str, args: &str, deposit: Balance) -> ReceiptId {
+        let signer = self.accounts.entry(signer_id.to_string()).or_default();
+        signer.balance = signer.balance.checked_sub(deposit).expect("Signer does not have enough NEAR");
+
+        let receipt_id = self.next_receipt_id;
+        self.next_receipt_id += 1;
+        self.queue.push(Receipt {
+            receipt_id,
+            signer_id: signer_id.to_string(),
+            predecessor_id: signer_id.to_string(),
+            receiver_id: receiver_id.to_string(),
+            actions: vec![Action::FunctionCall {
+                method_name: method_name.to_string(),
+                args: args.as_bytes().to_vec(),
+                deposit,
+                gas: DEFAULT_GAS,
+            }],
+            depends_on: vec![],
+        });
+        return receipt_id;
+    }
+
+    /// Execute queued receipts until none is left
+    pub fn run(&mut self) {
+        while self.step() {}
+    }
+
+    /// Execute the first queued receipt whose dependencies have completed.
+    /// Returns false if nothing could be executed.
+    pub fn step(&mut self) -> bool {
+        let position = match self.queue.iter().position(|r| r.depends_on.iter().all(|id| self.get_result(*id).is_some())) {
+            Some(x) => x,
+            None => return false,
+        };
+        let receipt = self.queue.remove(position);
+        self.block_index += 1;
+        self.execute(receipt);
+        return true;
+    }
+
+    /// Result of a receipt, following returned promises. None if it has not completed yet.
+    pub fn get_result(&self, receipt_id: ReceiptId) -> Option<Result<Vec<u8>, String>> {
+        match self.results.get(&receipt_id)? {
+            ReceiptStatus::Success(value) => return Some(Ok(value.clone())),
+            ReceiptStatus::Failure(message) => return Some(Err(message.clone())),
+            ReceiptStatus::Postponed(next_id) => return self.get_result(*next_id),
+        }
+    }
+
+    /// Read the state of the contract at `account_id`, e.g. to call its view methods
+    pub fn view<C: BorshDeserialize, T>(&mut self, account_id: &str, f: impl FnOnce(&C) -> T) -> T {
+        let storage = self.accounts.get(account_id).expect("Unknown account").storage.clone();
+        let record = Rc::new(RefCell::new(CallRecord::default()));
+        let context = self.get_context(account_id, account_id, account_id, vec![], 0, DEFAULT_GAS);
+        env::set_blockchain_interface(Box::new(SimulatedBlockchain::new(
+            MockedBlockchain::new(context, Default::default(), Default::default(), vec![], storage, Default::default()),
+            record,
+        )));
+        let contract: C = env::state_read().expect("Contract is not initialized");
+        let result = f(&contract);
+        env::take_blockchain_interface();
+        return result;
+    }
+
+    fn get_context(&self, signer_id: &str, predecessor_id: &str, receiver_id: &str, input: Vec<u8>, attached_deposit: Balance, prepaid_gas: Gas) -> VMContext {
+        let account = self.accounts.get(receiver_id);
+        VMContext {
+            current_account_id: receiver_id.to_string(),
+            signer_account_id: signer_id.to_string(),
+            signer_account_pk: vec![0, 1, 2],
+            predecessor_account_id: predecessor_id.to_string(),
+            input,
+            block_index: self.block_index,
+            block_timestamp: self.block_index * 1_000_000_000,
+            account_balance: account.map(|a| a.balance).unwrap_or(0),
+            account_locked_balance: 0,
+            storage_usage: account.map(|a| a.storage_usage).unwrap_or(0),
+            attached_deposit,
+            prepaid_gas,
+            random_seed: vec![0, 1, 2],
+            is_view: false,
+            output_data_receivers: vec![],
+            epoch_height: 0,
+        }
+    }
+
+    fn execute(&mut self, receipt: Receipt) {
+        let account = self.accounts.entry(receipt.receiver_id.clone()).or_default();
+        let snapshot = (account.balance, account.storage.clone(), account.storage_usage);
+
+        let mut logs = vec![];
+        let mut created = vec![];
+        let mut value = vec![];
+        let mut returned_promise = None;
+        let mut failure = None;
+
+        for action in receipt.actions.iter() {
+            match action {
+                Action::Transfer { deposit } => {
+                    self.accounts.get_mut(&receipt.receiver_id).unwrap().balance += deposit;
+                    value = vec![];
+                },
+                Action::FunctionCall { method_name, args, deposit, gas } => {
+                    self.accounts.get_mut(&receipt.receiver_id).unwrap().balance += deposit;
+                    let record = Rc::new(RefCell::new(CallRecord::default()));
+                    let output = self.call_handler(&receipt, method_name, args, *deposit, *gas, record.clone());
+                    logs.extend(record.borrow().logs.clone());
+                    match output {
+                        Ok(output) => {
+                            value = output.value;
+                            returned_promise = output.returned_promise.map(|index| index + created.len() as u64);
+                            let offset = created.len() as u64;
+                            created.extend(output.receipts.into_iter().map(|mut r| {
+                                r.receipt_indices.iter_mut().for_each(|i| *i += offset);
+                                r
+                            }));
+                        },
+                        Err(message) => {
+                            failure = Some(message);
+                            break;
+                        },
+                    }
+                },
+            }
+        }
+
+        let status = match failure {
+            Some(message) => {
+                // Revert the receipt and refund the attached deposits
+                let account = self.accounts.get_mut(&receipt.receiver_id).unwrap();
+                account.balance = snapshot.0;
+                account.storage = snapshot.1;
+                account.storage_usage = snapshot.2;
+                let deposit: Balance = receipt.actions.iter().map(|a| match a {
+                    Action::FunctionCall { deposit, .. } => *deposit,
+                    Action::Transfer { deposit } => *deposit,
+                }).sum();
+                self.accounts.entry(receipt.predecessor_id.clone()).or_default().balance += deposit;
+                ReceiptStatus::Failure(message)
+            },
+            None => {
+                let first_id = self.next_receipt_id;
+                self.next_receipt_id += created.len() as u64;
+                for (index, created_receipt) in created.into_iter().enumerate() {
+                    self.queue_receipt(&receipt, first_id + index as u64, first_id, created_receipt);
+                }
+                match returned_promise {
+                    Some(index) => ReceiptStatus::Postponed(first_id + index),
+                    None => ReceiptStatus::Success(value),
+                }
+            },
+        };
+
+        self.results.insert(receipt.receipt_id, status.clone());
+        self.outcomes.push(Outcome {
+            receipt,
+            block_index: self.block_index,
+            status,
+            logs,
+        });
+    }
+
+    /// Queue a receipt created by `parent`, paying its deposits from the contract
+    fn queue_receipt(&mut self, parent: &Receipt, receipt_id: ReceiptId, first_id: ReceiptId, created: CreatedReceipt) {
+        let actions: Vec<Action> = created.actions.into_iter().map(|a| match a {
+            CreatedAction::FunctionCall { method_name, args, gas, deposit } => Action::FunctionCall {
+                method_name,
+                args: args.into_bytes(),
+                deposit,
+                gas,
+            },
+            CreatedAction::Transfer { deposit } => Action::Transfer { deposit },
+        }).collect();
+
+        let deposit: Balance = actions.iter().map(|a| match a {
+            Action::FunctionCall { deposit, .. } => *deposit,
+            Action::Transfer { deposit } => *deposit,
+        }).sum();
+        let contract = self.accounts.get_mut(&parent.receiver_id).unwrap();
+        contract.balance = contract.balance.checked_sub(deposit).expect("Contract does not have enough NEAR for the attached deposit");
+
+        self.queue.push(Receipt {
+            receipt_id,
+            signer_id: parent.signer_id.clone(),
+            predecessor_id: parent.receiver_id.clone(),
+            receiver_id: created.receiver_id,
+            actions,
+            depends_on: created.receipt_indices.iter().map(|i| first_id + i).collect(),
+        });
+    }
+
+    fn call_handler(&mut self, receipt: &Receipt, method_name: &str, args: &[u8], deposit: Balance, gas: Gas, record: Rc<RefCell<CallRecord>>) -> Result<CallOutput, String> {
+        let fault = self.faults.iter().position(|(account_id, name, _)| *account_id == receipt.receiver_id && name == method_name);
+        if let Some(index) = fault {
+            match self.faults.remove(index).2 {
+                Fault::Panic => return Err(format!("Injected panic in {}.{}", receipt.receiver_id, method_name)),
+                Fault::OutOfGas => return Err(String::from("Exceeded the prepaid gas")),
+            }
+        }
+
+        let promise_results = receipt.depends_on.iter().map(|id| match self.get_result(*id).unwrap() {
+            Ok(value) => PromiseResult::Successful(value),
+            Err(_) => PromiseResult::Failed,
+        }).collect();
+        let context = self.get_context(&receipt.signer_id, &receipt.predecessor_id, &receipt.receiver_id, args.to_vec(), deposit, gas);
+        let account = self.accounts.get(&receipt.receiver_id).unwrap();
+        let handler = match &account.handler {
+            Some(x) => x,
+            None => return Err(format!("Account {} has no contract", receipt.receiver_id)),
+        };
+
+        env::set_blockchain_interface(Box::new(SimulatedBlockchain::new(
+            MockedBlockchain::new(context, Default::default(), Default::default(), promise_results, account.storage.clone(), Default::default()),
+            record.clone(),
+        )));
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            let value = handler(method_name, args);
+            return (value, env::storage_usage());
+        }));
+        let mut blockchain = env::take_blockchain_interface().unwrap();
+        let mocked = blockchain.as_mut_mocked_blockchain().unwrap();
+
+        let (value, storage_usage) = match result {
+            Ok(x) => x,
+            Err(payload) => {
+                let message = match payload.downcast_ref::<String>() {
+                    Some(message) => message.clone(),
+                    None => payload.downcast_ref::<&str>().map(|m| m.to_string()).unwrap_or_default(),
+                };
+                return Err(message);
+            },
+        };
+
+        let receipts = serde_json::to_string(mocked.created_receipts()).unwrap();
+        let account = self.accounts.get_mut(&receipt.receiver_id).unwrap();
+        account.storage = mocked.take_storage();
+        account.storage_usage = storage_usage;
+
+        return Ok(CallOutput {
+            value,
+            receipts: serde_json::from_str(&receipts).expect("Contract created an action the simulator does not support"),
+            returned_promise: record.borrow().returned_promise,
+        });
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_failed_call_is_reverted() {
+        let mut sim = Simulator::new();
+        sim.deploy("counter.near", Box::new(|method: &str, _input: &[u8]| -> Vec<u8> {
+            env::storage_write(b"count", method.as_bytes());
+            if method == "fail" {
+                env::panic(b"Failed on purpose");
+            }
+            return b"null".to_vec();
+        }));
+
+        sim.call("bob.near", "counter.near", "ok", "", 5).unwrap();
+        let error = sim.call("bob.near", "counter.near", "fail", "", 7).unwrap_err();
+        assert!(error.contains("Failed on purpose"));
+
+        // Storage is not written and the deposit is refunded
+        assert_eq!(sim.get_balance("bob.near"), DEFAULT_BALANCE - 5);
+        assert_eq!(sim.get_balance("counter.near"), DEFAULT_BALANCE + 5);
+        assert_eq!(sim.accounts["counter.near"].storage.get(b"count".as_ref()), Some(&b"ok".to_vec()));
+        assert_eq!(sim.block_index, 2);
+    }
+}
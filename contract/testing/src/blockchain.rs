@@ -0,0 +1,122 @@
+/*
+ * Mocked blockchain that records what the contract did.
+ *
+ * Wraps near-sdk MockedBlockchain and records the log lines and the promise passed
+ * to promise_return(), which MockedBlockchain does not expose. Contract methods called
+ * directly in Rust return their values instead of calling value_return(),
+ * so the value is not recorded.
+ *
+ * In the mocked blockchain the memory pointers given by the contract are plain
+ * addresses in the test process, so they can be read directly.
+ */
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use near_sdk::{ BlockchainInterface, MockedBlockchain };
+
+/// What a contract call did besides its storage and receipts
+#[derive(Default, Debug)]
+pub struct CallRecord {
+
+    // Lines logged with env::log()
+    pub logs: Vec<String>,
+
+    // Promise index the call returned with promise_return()
+    pub returned_promise: Option<u64>,
+}
+
+pub struct SimulatedBlockchain {
+    inner: MockedBlockchain,
+    record: Rc<RefCell<CallRecord>>,
+}
+
+impl SimulatedBlockchain {
+
+    pub fn new(inner: MockedBlockchain, record: Rc<RefCell<CallRecord>>) -> Self {
+        Self { inner, record }
+    }
+}
+
+/// Pass the call through to the wrapped MockedBlockchain
+macro_rules! delegate {
+    ($($name:ident($($arg:ident: $ty:ty),*) $(-> $ret:ty)?;)*) => {
+        $(
+            unsafe fn $name(&self, $($arg: $ty),*) $(-> $ret)? {
+                self.inner.$name($($arg),*)
+            }
+        )*
+    };
+}
+
+#[allow(clippy::too_many_arguments)]
+impl BlockchainInterface for SimulatedBlockchain {
+
+    delegate! {
+        read_register(register_id: u64, ptr: u64);
+        register_len(register_id: u64) -> u64;
+        current_account_id(register_id: u64);
+        signer_account_id(register_id: u64);
+        signer_account_pk(register_id: u64);
+        predecessor_account_id(register_id: u64);
+        input(register_id: u64);
+        block_index() -> u64;
+        block_timestamp() -> u64;
+        epoch_height() -> u64;
+        storage_usage() -> u64;
+        account_balance(balance_ptr: u64);
+        account_locked_balance(balance_ptr: u64);
+        attached_deposit(balance_ptr: u64);
+        prepaid_gas() -> u64;
+        used_gas() -> u64;
+        random_seed(register_id: u64);
+        sha256(value_len: u64, value_ptr: u64, register_id: u64);
+        keccak256(value_len: u64, value_ptr: u64, register_id: u64);
+        keccak512(value_len: u64, value_ptr: u64, register_id: u64);
+        value_return(value_len: u64, value_ptr: u64);
+        panic();
+        panic_utf8(len: u64, ptr: u64);
+        log_utf16(len: u64, ptr: u64);
+        promise_create(account_id_len: u64, account_id_ptr: u64, method_name_len: u64, method_name_ptr: u64, arguments_len: u64, arguments_ptr: u64, amount_ptr: u64, gas: u64) -> u64;
+        promise_then(promise_index: u64, account_id_len: u64, account_id_ptr: u64, method_name_len: u64, method_name_ptr: u64, arguments_len: u64, arguments_ptr: u64, amount_ptr: u64, gas: u64) -> u64;
+        promise_and(promise_idx_ptr: u64, promise_idx_count: u64) -> u64;
+        promise_batch_create(account_id_len: u64, account_id_ptr: u64) -> u64;
+        promise_batch_then(promise_index: u64, account_id_len: u64, account_id_ptr: u64) -> u64;
+        promise_batch_action_create_account(promise_index: u64);
+        promise_batch_action_deploy_contract(promise_index: u64, code_len: u64, code_ptr: u64);
+        promise_batch_action_function_call(promise_index: u64, method_name_len: u64, method_name_ptr: u64, arguments_len: u64, arguments_ptr: u64, amount_ptr: u64, gas: u64);
+        promise_batch_action_transfer(promise_index: u64, amount_ptr: u64);
+        promise_batch_action_stake(promise_index: u64, amount_ptr: u64, public_key_len: u64, public_key_ptr: u64);
+        promise_batch_action_add_key_with_full_access(promise_index: u64, public_key_len: u64, public_key_ptr: u64, nonce: u64);
+        promise_batch_action_add_key_with_function_call(promise_index: u64, public_key_len: u64, public_key_ptr: u64, nonce: u64, allowance_ptr: u64, receiver_id_len: u64, receiver_id_ptr: u64, method_names_len: u64, method_names_ptr: u64);
+        promise_batch_action_delete_key(promise_index: u64, public_key_len: u64, public_key_ptr: u64);
+        promise_batch_action_delete_account(promise_index: u64, beneficiary_id_len: u64, beneficiary_id_ptr: u64);
+        promise_results_count() -> u64;
+        promise_result(result_idx: u64, register_id: u64) -> u64;
+        storage_write(key_len: u64, key_ptr: u64, value_len: u64, value_ptr: u64, register_id: u64) -> u64;
+        storage_read(key_len: u64, key_ptr: u64, register_id: u64) -> u64;
+        storage_remove(key_len: u64, key_ptr: u64, register_id: u64) -> u64;
+        storage_has_key(key_len: u64, key_ptr: u64) -> u64;
+        validator_stake(account_id_len: u64, account_id_ptr: u64, stake_ptr: u64);
+        validator_total_stake(stake_ptr: u64);
+    }
+
+    unsafe fn log_utf8(&self, len: u64, ptr: u64) {
+        self.inner.log_utf8(len, ptr);
+        let message = std::slice::from_raw_parts(ptr as *const u8, len as usize);
+        self.record.borrow_mut().logs.push(String::from_utf8_lossy(message).into_owned());
+    }
+
+    unsafe fn promise_return(&self, promise_id: u64) {
+        self.inner.promise_return(promise_id);
+        self.record.borrow_mut().returned_promise = Some(promise_id);
+    }
+
+    fn as_mut_mocked_blockchain(&mut self) -> Option<&mut MockedBlockchain> {
+        Some(&mut self.inner)
+    }
+
+    fn as_mocked_blockchain(&self) -> Option<&MockedBlockchain> {
+        Some(&self.inner)
+    }
+}
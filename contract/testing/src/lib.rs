@@ -0,0 +1,276 @@
+/*
+ * Mocked blockchain fixtures for contract unit tests.
+ *
+ * Replaces the get_context() and callback_env() helpers each test module used to copy:
+ *
+ *     set_context(ContextBuilder::new().predecessor_account_id(bob()).attached_deposit(1).build());
+ *     contract.send(carol(), 100, vec![]);
+ *     assert_logged("transfer_started");
+ *
+ * Storage is kept when the context changes, like with testing_env!, so a test can
+ * switch the caller or advance blocks between calls. Call reset() to start from empty storage.
+ */
+
+#![cfg(not(target_arch = "wasm32"))]
+
+#![allow(clippy::needless_return)]
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use near_sdk::serde_json::{ self, Value };
+use near_sdk::{ env, AccountId, Balance, BlockHeight, Gas, MockedBlockchain, PromiseOrValue, PromiseResult, StorageUsage, VMContext };
+
+pub mod blockchain;
+
+use crate::blockchain::{ CallRecord, SimulatedBlockchain };
+
+/// Prefix of NEP-297 event log lines
+pub const EVENT_JSON_PREFIX: &str = "EVENT_JSON:";
+
+/// Time between blocks when advancing blocks, one second in nanoseconds
+pub const BLOCK_TIME: u64 = 1_000_000_000;
+
+pub fn alice() -> AccountId {
+    "alice.near".to_string()
+}
+
+pub fn bob() -> AccountId {
+    "bob.near".to_string()
+}
+
+pub fn carol() -> AccountId {
+    "carol.near".to_string()
+}
+
+/**
+ * Builds the context of a contract call.
+ *
+ * By default alice.near is the contract and bob.near calls it
+ * without a deposit, with plenty of gas and NEAR.
+ */
+pub struct ContextBuilder {
+    context: VMContext,
+}
+
+impl Default for ContextBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ContextBuilder {
+
+    pub fn new() -> Self {
+        Self {
+            context: VMContext {
+                current_account_id: alice(),
+                signer_account_id: bob(),
+                signer_account_pk: vec![0, 1, 2],
+                predecessor_account_id: bob(),
+                input: vec![],
+                block_index: 0,
+                block_timestamp: 0,
+                account_balance: 1_000_000_000_000_000_000_000_000_000u128,
+                account_locked_balance: 0,
+                storage_usage: 10u64.pow(6),
+                attached_deposit: 0,
+                prepaid_gas: 10u64.pow(18),
+                random_seed: vec![0, 1, 2],
+                is_view: false,
+                output_data_receivers: vec![],
+                epoch_height: 0,
+            },
+        }
+    }
+
+    pub fn current_account_id(mut self, account_id: AccountId) -> Self {
+        self.context.current_account_id = account_id;
+        self
+    }
+
+    pub fn signer_account_id(mut self, account_id: AccountId) -> Self {
+        self.context.signer_account_id = account_id;
+        self
+    }
+
+    pub fn predecessor_account_id(mut self, account_id: AccountId) -> Self {
+        self.context.predecessor_account_id = account_id;
+        self
+    }
+
+    pub fn attached_deposit(mut self, amount: Balance) -> Self {
+        self.context.attached_deposit = amount;
+        self
+    }
+
+    pub fn account_balance(mut self, amount: Balance) -> Self {
+        self.context.account_balance = amount;
+        self
+    }
+
+    /// Also moves the block timestamp, one block per BLOCK_TIME
+    pub fn block_index(mut self, block_index: BlockHeight) -> Self {
+        self.context.block_index = block_index;
+        self.context.block_timestamp = block_index * BLOCK_TIME;
+        self
+    }
+
+    pub fn block_timestamp(mut self, timestamp: u64) -> Self {
+        self.context.block_timestamp = timestamp;
+        self
+    }
+
+    pub fn storage_usage(mut self, usage: StorageUsage) -> Self {
+        self.context.storage_usage = usage;
+        self
+    }
+
+    pub fn prepaid_gas(mut self, gas: Gas) -> Self {
+        self.context.prepaid_gas = gas;
+        self
+    }
+
+    pub fn is_view(mut self, is_view: bool) -> Self {
+        self.context.is_view = is_view;
+        self
+    }
+
+    pub fn build(self) -> VMContext {
+        return self.context;
+    }
+}
+
+/// Context of a call by `predecessor_account_id` with the defaults of ContextBuilder
+pub fn get_context(predecessor_account_id: AccountId) -> VMContext {
+    return ContextBuilder::new().predecessor_account_id(predecessor_account_id).build();
+}
+
+/// Context of a call by `predecessor_account_id` attaching `attached_deposit`, e.g. the confirmation deposit of the token
+pub fn get_deposit_context(predecessor_account_id: AccountId, attached_deposit: Balance) -> VMContext {
+    return ContextBuilder::new().predecessor_account_id(predecessor_account_id).attached_deposit(attached_deposit).build();
+}
+
+thread_local! {
+    static CURRENT: RefCell<Option<(VMContext, Rc<RefCell<CallRecord>>)>> = const { RefCell::new(None) };
+}
+
+fn install(context: VMContext, promise_results: Vec<PromiseResult>) {
+    let storage = match env::take_blockchain_interface() {
+        Some(mut blockchain) => blockchain.as_mut_mocked_blockchain().unwrap().take_storage(),
+        None => Default::default(),
+    };
+    let record = Rc::new(RefCell::new(CallRecord::default()));
+    env::set_blockchain_interface(Box::new(SimulatedBlockchain::new(
+        MockedBlockchain::new(context.clone(), Default::default(), Default::default(), promise_results, storage, Default::default()),
+        record.clone(),
+    )));
+    CURRENT.with(|current| *current.borrow_mut() = Some((context, record)));
+}
+
+/// Make `context` the current call, keeping the storage
+pub fn set_context(context: VMContext) {
+    install(context, vec![]);
+}
+
+/// Make `context` a callback that receives `result` from the promise it waited for
+pub fn set_callback_context(context: VMContext, result: PromiseResult) {
+    install(context, vec![result]);
+}
+
+/// Drop the storage and the current context
+pub fn reset() {
+    env::take_blockchain_interface();
+    CURRENT.with(|current| *current.borrow_mut() = None);
+}
+
+/// The current context, as given to set_context()
+pub fn current_context() -> VMContext {
+    return CURRENT.with(|current| current.borrow().as_ref().expect("Context is not set").0.clone());
+}
+
+/// Move the current context `blocks` blocks forward, keeping the storage
+pub fn advance_blocks(blocks: BlockHeight) {
+    let context = current_context();
+    let timestamp = context.block_timestamp + blocks * BLOCK_TIME;
+    set_context(VMContext {
+        block_index: context.block_index + blocks,
+        block_timestamp: timestamp,
+        ..context
+    });
+}
+
+/// Lines logged since the context was last set
+pub fn get_logs() -> Vec<String> {
+    return CURRENT.with(|current| match current.borrow().as_ref() {
+        Some((_, record)) => record.borrow().logs.clone(),
+        None => vec![],
+    });
+}
+
+/// NEP-297 events logged since the context was last set
+pub fn get_events() -> Vec<Value> {
+    return get_logs().iter()
+        .filter(|line| line.starts_with(EVENT_JSON_PREFIX))
+        .map(|line| serde_json::from_str(&line[EVENT_JSON_PREFIX.len()..]).expect("Invalid event JSON"))
+        .collect();
+}
+
+/// Panic unless a line containing `fragment` was logged since the context was last set
+pub fn assert_logged(fragment: &str) {
+    let logs = get_logs();
+    assert!(logs.iter().any(|line| line.contains(fragment)), "No log line contains {:?}, got {:?}", fragment, logs);
+}
+
+/// Panic unless an event named `event` was logged since the context was last set
+pub fn assert_event(event: &str) {
+    let events = get_events();
+    assert!(events.iter().any(|e| e["event"] == event), "No {} event, got {:?}", event, events);
+}
+
+/// The value a contract method returned, panics if it returned a promise instead
+pub fn unwrap_value<T>(result: PromiseOrValue<T>) -> T {
+    match result {
+        PromiseOrValue::Value(value) => return value,
+        PromiseOrValue::Promise(_) => panic!("Expected a value, got a promise"),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_context_keeps_storage() {
+        reset();
+        set_context(get_context(carol()));
+        env::storage_write(b"key", b"value");
+        assert_eq!(env::predecessor_account_id(), carol());
+
+        advance_blocks(5);
+        assert_eq!(env::block_index(), 5);
+        assert_eq!(env::block_timestamp(), 5 * BLOCK_TIME);
+        assert_eq!(env::storage_read(b"key"), Some(b"value".to_vec()));
+
+        reset();
+        set_context(get_context(bob()));
+        assert_eq!(env::storage_read(b"key"), None);
+    }
+
+    #[test]
+    fn test_logs() {
+        reset();
+        set_context(ContextBuilder::new().attached_deposit(1).build());
+        assert_eq!(env::attached_deposit(), 1);
+        env::log(b"hello");
+        env::log(b"EVENT_JSON:{\"standard\":\"nep9000\",\"version\":\"1.0.0\",\"event\":\"mint\",\"data\":{}}");
+        assert_logged("hello");
+        assert_event("mint");
+        assert_eq!(get_events().len(), 1);
+
+        // A new context starts a new call
+        set_context(get_context(bob()));
+        assert!(get_logs().is_empty());
+    }
+}
@@ -0,0 +1,145 @@
+/*
+ * Historical values by block.
+ *
+ * A checkpoint is written whenever a balance or the total supply changes.
+ * Governance contracts can then ask what the value was at a past block,
+ * so tokens moved after a snapshot block cannot vote twice.
+ *
+ * Only the last change within a block is kept. Query blocks that have already
+ * passed, as the value of the current block can still change.
+ *
+ * The history grows with every block the value changes in. The ledger charges
+ * the growth to the storage stake of the account that caused the change,
+ * so nobody can grow the history of another account at its expense.
+ * The history is kept when an account unregisters.
+ */
+
+use near_sdk::borsh::{ self, BorshDeserialize, BorshSerialize };
+use near_sdk::collections::{ LookupMap, Vector };
+use near_sdk::{ AccountId, Balance, BlockHeight };
+
+#[derive(BorshDeserialize, BorshSerialize, Clone, PartialEq, Debug)]
+pub struct Checkpoint {
+
+    /// The block where the value changed
+    pub block_index: BlockHeight,
+
+    /// The value at the end of the block
+    pub value: Balance,
+}
+
+/// Record a new value at `block_index`. Checkpoints must be written in block order.
+pub fn write_checkpoint(checkpoints: &mut Vector<Checkpoint>, block_index: BlockHeight, value: Balance) {
+    let checkpoint = Checkpoint { block_index, value };
+    let len = checkpoints.len();
+    if len > 0 && checkpoints.get(len - 1).unwrap().block_index == block_index {
+        checkpoints.replace(len - 1, &checkpoint);
+    } else {
+        checkpoints.push(&checkpoint);
+    }
+}
+
+/// The value at the end of `block_index`, or 0 if nothing was recorded by then
+pub fn value_at(checkpoints: &Vector<Checkpoint>, block_index: BlockHeight) -> Balance {
+    // Find the first checkpoint after the block, the one before it holds the value
+    let mut low = 0;
+    let mut high = checkpoints.len();
+    while low < high {
+        let mid = low + (high - low) / 2;
+        if checkpoints.get(mid).unwrap().block_index > block_index {
+            high = mid;
+        } else {
+            low = mid + 1;
+        }
+    }
+    if low == 0 {
+        return 0;
+    }
+    checkpoints.get(low - 1).unwrap().value
+}
+
+/// The latest value, or 0 if nothing was recorded
+pub fn latest_value(checkpoints: &Vector<Checkpoint>) -> Balance {
+    match checkpoints.len() {
+        0 => 0,
+        len => checkpoints.get(len - 1).unwrap().value,
+    }
+}
+
+/// Value history for each account
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct AccountCheckpoints {
+
+    pub checkpoints: LookupMap<AccountId, Vector<Checkpoint>>,
+
+    /// Storage prefix of the checkpoint vectors, followed by the account id
+    pub vector_prefix: Vec<u8>,
+}
+
+impl AccountCheckpoints {
+
+    pub fn new(prefix: &[u8], vector_prefix: &[u8]) -> Self {
+        Self {
+            checkpoints: LookupMap::new(prefix.to_vec()),
+            vector_prefix: vector_prefix.to_vec(),
+        }
+    }
+
+    /// Record a new value of `account_id` at `block_index`
+    pub fn write(&mut self, account_id: &AccountId, block_index: BlockHeight, value: Balance) {
+        let mut checkpoints = match self.checkpoints.get(account_id) {
+            Some(x) => x,
+            None => {
+                let mut prefix = self.vector_prefix.clone();
+                prefix.extend_from_slice(account_id.as_bytes());
+                Vector::new(prefix)
+            },
+        };
+        write_checkpoint(&mut checkpoints, block_index, value);
+        self.checkpoints.insert(account_id, &checkpoints);
+    }
+
+    /// The value of `account_id` at the end of `block_index`
+    pub fn value_at(&self, account_id: &AccountId, block_index: BlockHeight) -> Balance {
+        match self.checkpoints.get(account_id) {
+            Some(checkpoints) => value_at(&checkpoints, block_index),
+            None => 0,
+        }
+    }
+
+    /// The latest value of `account_id`
+    pub fn latest_value(&self, account_id: &AccountId) -> Balance {
+        match self.checkpoints.get(account_id) {
+            Some(checkpoints) => latest_value(&checkpoints),
+            None => 0,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nep9000_testing::{ set_context, ContextBuilder };
+
+    #[test]
+    fn test_value_at() {
+        set_context(ContextBuilder::new().build());
+        let mut checkpoints = Vector::new(b"c".to_vec());
+        assert_eq!(value_at(&checkpoints, 5), 0);
+
+        write_checkpoint(&mut checkpoints, 10, 100);
+        write_checkpoint(&mut checkpoints, 20, 200);
+        write_checkpoint(&mut checkpoints, 20, 250);
+        write_checkpoint(&mut checkpoints, 30, 0);
+        assert_eq!(checkpoints.len(), 3);
+
+        assert_eq!(value_at(&checkpoints, 9), 0);
+        assert_eq!(value_at(&checkpoints, 10), 100);
+        assert_eq!(value_at(&checkpoints, 19), 100);
+        assert_eq!(value_at(&checkpoints, 20), 250);
+        assert_eq!(value_at(&checkpoints, 29), 250);
+        assert_eq!(value_at(&checkpoints, 30), 0);
+        assert_eq!(value_at(&checkpoints, 1000), 0);
+    }
+}
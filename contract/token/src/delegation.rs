@@ -0,0 +1,244 @@
+/*
+ * Vote delegation.
+ *
+ * A holder can let another account vote with its balance without moving the tokens.
+ * Votes follow the balance: whenever the balance of a holder changes,
+ * the same amount moves to or from the votes of its delegate.
+ *
+ * Tokens locked in a pending transfer are already part of the receiver's balance,
+ * so they vote with the receiver's delegate until the transfer is rolled back.
+ *
+ * Balances of holders without a delegate do not vote.
+ * A holder who wants to vote itself delegates to its own account.
+ */
+
+use near_sdk::borsh::{ self, BorshDeserialize, BorshSerialize };
+use near_sdk::collections::LookupMap;
+use near_sdk::{ env, near_bindgen, AccountId, Balance, BlockHeight };
+
+use crate::checkpoints::AccountCheckpoints;
+use crate::errors::UnwrapOrPanic;
+use crate::events::TokenEvent;
+use crate::token::Token;
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct Delegation {
+
+    // Holder -> the account voting with its balance
+    pub delegates: LookupMap<AccountId, AccountId>,
+
+    // Votes of each delegate by block
+    pub votes: AccountCheckpoints,
+}
+
+impl Delegation {
+
+    pub fn get_delegate(&self, owner_id: &AccountId) -> Option<AccountId> {
+        self.delegates.get(owner_id)
+    }
+
+    pub fn get_votes(&self, account_id: &AccountId) -> Balance {
+        self.votes.latest_value(account_id)
+    }
+
+    /// Votes of `account_id` at the end of block `block_index`
+    pub fn get_votes_at(&self, account_id: &AccountId, block_index: BlockHeight) -> Balance {
+        self.votes.value_at(account_id, block_index)
+    }
+
+    /// Move the votes of `owner_id`, who holds `balance`, to `delegate_id`
+    pub fn delegate(&mut self, owner_id: &AccountId, delegate_id: &AccountId, balance: Balance) {
+        let old_delegate = self.delegates.insert(owner_id, delegate_id);
+        if let Some(old_delegate) = old_delegate {
+            self.sub_votes(&old_delegate, balance);
+        }
+        self.add_votes(delegate_id, balance);
+    }
+
+    /// Update the votes of the delegate of `owner_id` after its balance changed
+    pub fn move_votes(&mut self, owner_id: &AccountId, old_balance: Balance, new_balance: Balance) {
+        let delegate_id = match self.delegates.get(owner_id) {
+            Some(x) => x,
+            None => return,
+        };
+        if new_balance > old_balance {
+            self.add_votes(&delegate_id, new_balance - old_balance);
+        } else if new_balance < old_balance {
+            self.sub_votes(&delegate_id, old_balance - new_balance);
+        }
+    }
+
+    fn add_votes(&mut self, account_id: &AccountId, amount: Balance) {
+        let votes = self.get_votes(account_id) + amount;
+        self.votes.write(account_id, env::block_index(), votes);
+    }
+
+    fn sub_votes(&mut self, account_id: &AccountId, amount: Balance) {
+        // Votes of a delegate are the sum of its holders' balances
+        let votes = self.get_votes(account_id).checked_sub(amount).expect("Votes cannot go to negative");
+        self.votes.write(account_id, env::block_index(), votes);
+    }
+}
+
+#[near_bindgen]
+impl Token {
+
+    /**
+     * Let `delegate_id` vote with the caller's balance, replacing the previous delegate.
+     *
+     * Delegate to your own account to vote yourself. The delegate must be registered.
+     * Requires the confirmation deposit, and the caller's storage stake must cover the vote history.
+     */
+    #[payable]
+    pub fn delegate(&mut self, delegate_id: AccountId) {
+        self.take_confirmation_deposit();
+        let owner_id = env::predecessor_account_id();
+        self.ledger.delegate(&owner_id, &delegate_id).unwrap_or_panic();
+        TokenEvent::DelegateChanged {
+            owner_id,
+            delegate_id,
+        }.emit();
+    }
+
+    /// Returns the account voting with the balance of `owner_id`, or null if none
+    pub fn get_delegate(&self, owner_id: AccountId) -> Option<AccountId> {
+        self.ledger.delegation.get_delegate(&owner_id)
+    }
+
+    /// Returns the current votes delegated to `account_id`
+    pub fn get_votes(&self, account_id: AccountId) -> Balance {
+        self.ledger.delegation.get_votes(&account_id)
+    }
+
+    /// Returns the votes delegated to `account_id` at the end of block `block_index`
+    pub fn get_votes_at(&self, account_id: AccountId, block_index: BlockHeight) -> Balance {
+        self.ledger.delegation.get_votes_at(&account_id, block_index)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nep9000_testing::{ alice, bob, carol, set_context, ContextBuilder };
+
+    use crate::test_utils::{ register_accounts, STAKE };
+    use crate::token::TransferStatus;
+
+    #[test]
+    fn test_delegate() {
+        set_context(ContextBuilder::new().predecessor_account_id(bob()).attached_deposit(1).block_index(1).build());
+        let mut contract = Token::new(bob(), 1_000u128, None, None, None);
+        register_accounts(&mut contract);
+        assert_eq!(contract.get_votes(bob()), 0);
+
+        contract.delegate(bob());
+        assert_eq!(contract.get_delegate(bob()), Some(bob()));
+        assert_eq!(contract.get_votes(bob()), 1_000);
+
+        set_context(ContextBuilder::new().predecessor_account_id(bob()).attached_deposit(1).block_index(2).build());
+        contract.delegate(alice());
+        assert_eq!(contract.get_votes(bob()), 0);
+        assert_eq!(contract.get_votes(alice()), 1_000);
+
+        set_context(ContextBuilder::new().predecessor_account_id(carol()).attached_deposit(1).block_index(3).build());
+        contract.delegate(alice());
+        assert_eq!(contract.get_votes(alice()), 1_000);
+
+        set_context(ContextBuilder::new().predecessor_account_id(bob()).attached_deposit(1).block_index(4).build());
+        contract.ft_transfer(carol(), 300.into(), None);
+        assert_eq!(contract.get_votes(alice()), 1_000);
+
+        set_context(ContextBuilder::new().predecessor_account_id(carol()).attached_deposit(1).block_index(5).build());
+        contract.delegate(carol());
+        assert_eq!(contract.get_votes(alice()), 700);
+        assert_eq!(contract.get_votes(carol()), 300);
+
+        assert_eq!(contract.get_votes_at(bob(), 1), 1_000);
+        assert_eq!(contract.get_votes_at(bob(), 2), 0);
+        assert_eq!(contract.get_votes_at(alice(), 4), 1_000);
+        assert_eq!(contract.get_votes_at(alice(), 5), 700);
+    }
+
+    #[test]
+    fn test_votes_follow_locked_transfers() {
+        set_context(ContextBuilder::new().predecessor_account_id(bob()).attached_deposit(1).block_index(1).build());
+        let mut contract = Token::new(bob(), 1_000u128, None, None, None);
+        register_accounts(&mut contract);
+        contract.delegate(bob());
+        set_context(ContextBuilder::new().predecessor_account_id(carol()).attached_deposit(1).block_index(1).build());
+        contract.delegate(carol());
+
+        // In transit tokens vote with the receiver
+        set_context(ContextBuilder::new().predecessor_account_id(bob()).attached_deposit(1).block_index(2).build());
+        let transfer_id = contract.ledger.send(bob(), carol(), 100, vec![]).unwrap_or_panic();
+        assert_eq!(contract.get_votes(bob()), 900);
+        assert_eq!(contract.get_votes(carol()), 100);
+
+        // and move back on rollback
+        contract.ledger.rollback(transfer_id).unwrap_or_panic();
+        assert_eq!(contract.ledger.get_transfer(transfer_id).unwrap().status, TransferStatus::RolledBack);
+        assert_eq!(contract.get_votes(bob()), 1_000);
+        assert_eq!(contract.get_votes(carol()), 0);
+
+        // Partially used transfers refund the rest
+        let transfer_id = contract.ledger.send(bob(), carol(), 100, vec![]).unwrap_or_panic();
+        contract.ledger.finalise_partial(transfer_id, 40).unwrap_or_panic();
+        assert_eq!(contract.get_votes(bob()), 960);
+        assert_eq!(contract.get_votes(carol()), 40);
+    }
+
+    #[test]
+    #[should_panic(expected = "Account carol.near is not registered")]
+    fn test_cannot_delegate_unregistered() {
+        set_context(ContextBuilder::new().predecessor_account_id(carol()).attached_deposit(1).block_index(1).build());
+        let mut contract = Token::new(bob(), 1_000u128, None, None, None);
+        contract.delegate(bob());
+    }
+
+    #[test]
+    #[should_panic(expected = "Account alice.near is not registered")]
+    fn test_cannot_delegate_to_unregistered() {
+        set_context(ContextBuilder::new().predecessor_account_id(bob()).attached_deposit(1).block_index(1).build());
+        let mut contract = Token::new(bob(), 1_000u128, None, None, None);
+        contract.delegate(alice());
+    }
+
+    #[test]
+    fn test_vote_history_is_charged_to_the_sender() {
+        set_context(ContextBuilder::new().predecessor_account_id(bob()).attached_deposit(1).block_index(1).build());
+        let mut contract = Token::new(bob(), 1_000u128, None, None, None);
+        contract.ledger.add_storage_deposit(&bob(), STAKE).unwrap_or_panic();
+        contract.ledger.register_sponsored(&alice(), 0).unwrap_or_panic();
+        let deposit = contract.ledger.get_storage_cost() * 10;
+        contract.ledger.register(&carol(), deposit).unwrap_or_panic();
+        contract.ft_transfer(carol(), 300.into(), None);
+        let required = contract.ledger.get_required_storage_deposit(&carol());
+
+        set_context(ContextBuilder::new().predecessor_account_id(carol()).attached_deposit(1).block_index(2).build());
+        contract.delegate(alice());
+        let delegated = contract.ledger.get_required_storage_deposit(&carol());
+        assert!(delegated > required);
+
+        // Balance changes sent by bob move the votes of alice on bob's stake
+        set_context(ContextBuilder::new().predecessor_account_id(bob()).attached_deposit(1).block_index(3).build());
+        let sender_required = contract.ledger.get_required_storage_deposit(&bob());
+        contract.ft_transfer(carol(), 100.into(), None);
+        assert_eq!(contract.get_votes(alice()), 400);
+        assert_eq!(contract.ledger.get_required_storage_deposit(&carol()), delegated);
+        assert!(contract.ledger.get_required_storage_deposit(&bob()) > sender_required);
+        assert_eq!(contract.ledger.get_required_storage_deposit(&alice()), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "Records of carol.near need a storage deposit of")]
+    fn test_delegate_needs_storage_deposit() {
+        set_context(ContextBuilder::new().predecessor_account_id(bob()).attached_deposit(1).block_index(1).build());
+        let mut contract = Token::new(bob(), 1_000u128, None, None, None);
+        let cost = contract.ledger.get_storage_cost();
+        contract.ledger.register(&carol(), cost).unwrap_or_panic();
+
+        set_context(ContextBuilder::new().predecessor_account_id(carol()).attached_deposit(1).block_index(1).build());
+        contract.delegate(bob());
+    }
+}
@@ -0,0 +1,250 @@
+/*
+ * Errors the token contract can fail with.
+ *
+ * Each error has a stable numeric code, so clients can map failures
+ * to their own messages without parsing the English text.
+ * The contract panics with the code in the message, e.g.
+ *
+ *     TOKEN_ERR_102: Not enough balance, need 11000, has 10000
+ *
+ * Codes of existing errors must never change. New errors get new codes.
+ *
+ * Ledger methods return these errors instead of panicking. A failed ledger method
+ * may have already written part of its state, so the caller should abort the call.
+ */
+
+use std::fmt;
+
+use near_sdk::{ env, AccountId, Balance, BlockHeight, Gas };
+
+use crate::token::TransferId;
+
+/// Panic messages start with this, followed by the error code
+pub const ERROR_PREFIX: &str = "TOKEN_ERR_";
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum TokenError {
+    // Accounts and balances
+    InvalidAccountId { account_id: AccountId },
+    ZeroAmount,
+    NotEnoughBalance { amount: Balance, balance: Balance },
+    BalanceLocked { amount: Balance, balance: Balance, locked: Balance },
+    SameOwner,
+    BurnLocked { amount: Balance, balance: Balance, locked: Balance },
+    EmptyBatch,
+    NotEnoughGas { required: Gas, available: Gas },
+
+    // Transfers
+    UnknownTransfer { transfer_id: TransferId },
+    TransferNotPending { transfer_id: TransferId },
+    TransferNotExpired { transfer_id: TransferId, until_block: BlockHeight },
+    TransferForwarded { transfer_id: TransferId },
+    AlreadyForwarded { transfer_id: TransferId },
+    ForwardTooMuch { transfer_id: TransferId, amount: Balance, transfer_amount: Balance },
+    UsedTooMuch { transfer_id: TransferId, amount_used: Balance, transfer_amount: Balance },
+    LockUnderflow { account_id: AccountId },
+
+    // Supply
+    MaxSupplyExceeded { amount: Balance, max_supply: Balance },
+    InitialSupplyExceedsMax,
+
+    // Allowances
+    NotEnoughAllowance { amount: Balance, allowance: Balance },
+    SelfAllowance,
+
+    // Storage
+    NotRegistered { account_id: AccountId },
+    AlreadyRegistered { account_id: AccountId },
+    RegistrationDeposit { required: Balance, attached: Balance },
+    StorageWithdrawTooMuch { amount: Balance, available: Balance },
+    UnregisterPendingTransfers,
+    UnregisterPositiveBalance,
+    StorageDepositTooLow { account_id: AccountId, required: Balance, deposit: Balance },
+
+    // Access
+    NotOwner,
+    NotMinter,
+    ConfirmationDeposit { required: Balance },
+    ZeroConfirmationDeposit,
+
+    // Metadata
+    IconNotDataUrl,
+    IconTooLong { max_length: usize },
+    InvalidMetadataHash { length: usize },
+    MetadataHashWithoutLink,
+}
+
+impl TokenError {
+
+    /// Stable numeric code of the error
+    pub fn code(&self) -> u32 {
+        match self {
+            TokenError::InvalidAccountId { .. } => 100,
+            TokenError::ZeroAmount => 101,
+            TokenError::NotEnoughBalance { .. } => 102,
+            TokenError::BalanceLocked { .. } => 103,
+            TokenError::SameOwner => 104,
+            TokenError::BurnLocked { .. } => 105,
+            TokenError::EmptyBatch => 106,
+            TokenError::NotEnoughGas { .. } => 107,
+
+            TokenError::UnknownTransfer { .. } => 200,
+            TokenError::TransferNotPending { .. } => 201,
+            TokenError::TransferNotExpired { .. } => 202,
+            TokenError::TransferForwarded { .. } => 203,
+            TokenError::AlreadyForwarded { .. } => 204,
+            TokenError::ForwardTooMuch { .. } => 205,
+            TokenError::UsedTooMuch { .. } => 206,
+            TokenError::LockUnderflow { .. } => 207,
+
+            TokenError::MaxSupplyExceeded { .. } => 300,
+            TokenError::InitialSupplyExceedsMax => 301,
+
+            TokenError::NotEnoughAllowance { .. } => 400,
+            TokenError::SelfAllowance => 401,
+
+            TokenError::NotRegistered { .. } => 500,
+            TokenError::AlreadyRegistered { .. } => 501,
+            TokenError::RegistrationDeposit { .. } => 502,
+            TokenError::StorageWithdrawTooMuch { .. } => 503,
+            TokenError::UnregisterPendingTransfers => 504,
+            TokenError::UnregisterPositiveBalance => 505,
+            TokenError::StorageDepositTooLow { .. } => 506,
+
+            TokenError::NotOwner => 600,
+            TokenError::NotMinter => 601,
+            TokenError::ConfirmationDeposit { .. } => 602,
+            TokenError::ZeroConfirmationDeposit => 603,
+
+            TokenError::IconNotDataUrl => 700,
+            TokenError::IconTooLong { .. } => 701,
+            TokenError::InvalidMetadataHash { .. } => 702,
+            TokenError::MetadataHashWithoutLink => 703,
+        }
+    }
+
+    /// Abort the contract call with this error
+    pub fn panic(&self) -> ! {
+        env::panic(format!("{}{}: {}", ERROR_PREFIX, self.code(), self).as_bytes())
+    }
+}
+
+impl fmt::Display for TokenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TokenError::InvalidAccountId { account_id } => write!(f, "Account ID {} is invalid", account_id),
+            TokenError::ZeroAmount => write!(f, "Amount must be positive"),
+            TokenError::NotEnoughBalance { amount, balance } => write!(f, "Not enough balance, need {}, has {}", amount, balance),
+            TokenError::BalanceLocked { amount, balance, locked } => write!(f, "Cannot send {} tokens, as account has {} and in tx lock {}", amount, balance, locked),
+            TokenError::SameOwner => write!(f, "The new owner should be different from the current owner"),
+            TokenError::BurnLocked { amount, balance, locked } => write!(f, "Cannot burn {} tokens, as account has {} and in tx lock {}", amount, balance, locked),
+            TokenError::EmptyBatch => write!(f, "Batch has no transfers"),
+            TokenError::NotEnoughGas { required, available } => write!(f, "Not enough gas, need {}, has {}", required, available),
+
+            TokenError::UnknownTransfer { transfer_id } => write!(f, "Unknown transfer {}", transfer_id),
+            TokenError::TransferNotPending { transfer_id } => write!(f, "Transfer {} is no longer pending", transfer_id),
+            TokenError::TransferNotExpired { transfer_id, until_block } => write!(f, "Transfer {} is locked until block {}", transfer_id, until_block),
+            TokenError::TransferForwarded { transfer_id } => write!(f, "Transfer {} is waiting for its follow-up transfer", transfer_id),
+            TokenError::AlreadyForwarded { transfer_id } => write!(f, "Transfer {} has already been forwarded", transfer_id),
+            TokenError::ForwardTooMuch { transfer_id, amount, transfer_amount } => write!(f, "Cannot forward {} tokens, transfer {} has {}", amount, transfer_id, transfer_amount),
+            TokenError::UsedTooMuch { transfer_id, amount_used, transfer_amount } => write!(f, "Receiver cannot use {} tokens, transfer {} has {}", amount_used, transfer_id, transfer_amount),
+            TokenError::LockUnderflow { account_id } => write!(f, "Locked balance of {} cannot go to negative", account_id),
+
+            TokenError::MaxSupplyExceeded { amount, max_supply } => write!(f, "Cannot mint {} tokens, total supply would exceed {}", amount, max_supply),
+            TokenError::InitialSupplyExceedsMax => write!(f, "Total supply cannot exceed max supply"),
+
+            TokenError::NotEnoughAllowance { amount, allowance } => write!(f, "Not enough allowance, need {}, has {}", amount, allowance),
+            TokenError::SelfAllowance => write!(f, "Can not increment allowance for yourself"),
+
+            TokenError::NotRegistered { account_id } => write!(f, "Account {} is not registered", account_id),
+            TokenError::AlreadyRegistered { account_id } => write!(f, "Account {} is already registered", account_id),
+            TokenError::RegistrationDeposit { required, attached } => write!(f, "Registering an account needs a deposit of {}, got {}", required, attached),
+            TokenError::StorageWithdrawTooMuch { amount, available } => write!(f, "Cannot withdraw {} from storage deposit, available {}", amount, available),
+            TokenError::UnregisterPendingTransfers => write!(f, "Cannot unregister an account with pending transfers"),
+            TokenError::UnregisterPositiveBalance => write!(f, "Cannot unregister an account with a positive balance without force"),
+            TokenError::StorageDepositTooLow { account_id, required, deposit } => write!(f, "Records of {} need a storage deposit of {}, has {}", account_id, required, deposit),
+
+            TokenError::NotOwner => write!(f, "Only the owner can call this method"),
+            TokenError::NotMinter => write!(f, "Only minters can call this method"),
+            TokenError::ConfirmationDeposit { required } => write!(f, "Requires attached deposit of exactly {} yoctoNEAR", required),
+            TokenError::ZeroConfirmationDeposit => write!(f, "Confirmation deposit cannot be zero"),
+
+            TokenError::IconNotDataUrl => write!(f, "Icon must be a data URL"),
+            TokenError::IconTooLong { max_length } => write!(f, "Icon can be at most {} bytes", max_length),
+            TokenError::InvalidMetadataHash { length } => write!(f, "Metadata hash must be {} bytes", length),
+            TokenError::MetadataHashWithoutLink => write!(f, "Metadata hash given without metadata link"),
+        }
+    }
+}
+
+/// Panic with the error code instead of returning the error
+pub trait UnwrapOrPanic<T> {
+    fn unwrap_or_panic(self) -> T;
+}
+
+impl<T> UnwrapOrPanic<T> for Result<T, TokenError> {
+    fn unwrap_or_panic(self) -> T {
+        match self {
+            Ok(value) => value,
+            Err(e) => e.panic(),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_codes_are_unique() {
+        let errors = vec![
+            TokenError::InvalidAccountId { account_id: "".to_string() },
+            TokenError::ZeroAmount,
+            TokenError::NotEnoughBalance { amount: 0, balance: 0 },
+            TokenError::BalanceLocked { amount: 0, balance: 0, locked: 0 },
+            TokenError::SameOwner,
+            TokenError::BurnLocked { amount: 0, balance: 0, locked: 0 },
+            TokenError::EmptyBatch,
+            TokenError::NotEnoughGas { required: 0, available: 0 },
+            TokenError::UnknownTransfer { transfer_id: 0 },
+            TokenError::TransferNotPending { transfer_id: 0 },
+            TokenError::TransferNotExpired { transfer_id: 0, until_block: 0 },
+            TokenError::TransferForwarded { transfer_id: 0 },
+            TokenError::AlreadyForwarded { transfer_id: 0 },
+            TokenError::ForwardTooMuch { transfer_id: 0, amount: 0, transfer_amount: 0 },
+            TokenError::UsedTooMuch { transfer_id: 0, amount_used: 0, transfer_amount: 0 },
+            TokenError::LockUnderflow { account_id: "".to_string() },
+            TokenError::MaxSupplyExceeded { amount: 0, max_supply: 0 },
+            TokenError::InitialSupplyExceedsMax,
+            TokenError::NotEnoughAllowance { amount: 0, allowance: 0 },
+            TokenError::SelfAllowance,
+            TokenError::NotRegistered { account_id: "".to_string() },
+            TokenError::AlreadyRegistered { account_id: "".to_string() },
+            TokenError::RegistrationDeposit { required: 0, attached: 0 },
+            TokenError::StorageWithdrawTooMuch { amount: 0, available: 0 },
+            TokenError::UnregisterPendingTransfers,
+            TokenError::UnregisterPositiveBalance,
+            TokenError::StorageDepositTooLow { account_id: "".to_string(), required: 0, deposit: 0 },
+            TokenError::NotOwner,
+            TokenError::NotMinter,
+            TokenError::ConfirmationDeposit { required: 0 },
+            TokenError::ZeroConfirmationDeposit,
+            TokenError::IconNotDataUrl,
+            TokenError::IconTooLong { max_length: 0 },
+            TokenError::InvalidMetadataHash { length: 0 },
+            TokenError::MetadataHashWithoutLink,
+        ];
+        let mut codes: Vec<u32> = errors.iter().map(|e| e.code()).collect();
+        codes.sort();
+        codes.dedup();
+        assert_eq!(codes.len(), errors.len());
+    }
+
+    #[test]
+    fn test_error_message() {
+        let error = TokenError::NotEnoughBalance { amount: 2, balance: 1 };
+        assert_eq!(error.code(), 102);
+        assert_eq!(error.to_string(), "Not enough balance, need 2, has 1");
+    }
+}
@@ -0,0 +1,125 @@
+/*
+ * Structured event logs.
+ *
+ * Each balance change is logged as a NEP-297 event, so indexers can rebuild
+ * balances off-chain without parsing free-form log lines:
+ *
+ *     EVENT_JSON:{"standard":"nep9000","version":"1.0.0","event":"mint","data":{...}}
+ *
+ * Transfers created by send() move the tokens to the receiver at transfer_started.
+ * transfer_finalised refunds `refunded` tokens back to the sender,
+ * transfer_rolled_back returns the whole amount to the sender.
+ *
+ * https://nomicon.io/Standards/EventsFormat.html
+ */
+
+use near_sdk::serde::Serialize;
+use near_sdk::serde_json;
+use near_sdk::{ env, AccountId };
+use near_sdk::json_types::U128;
+
+use crate::metadata::Metadata;
+use crate::token::TransferId;
+
+pub const EVENT_STANDARD: &str = "nep9000";
+
+pub const EVENT_VERSION: &str = "1.0.0";
+
+const EVENT_JSON_PREFIX: &str = "EVENT_JSON:";
+
+#[derive(Serialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(tag = "event", content = "data", rename_all = "snake_case")]
+pub enum TokenEvent {
+    Mint {
+        owner_id: AccountId,
+        amount: U128,
+    },
+    Burn {
+        owner_id: AccountId,
+        amount: U128,
+    },
+    /// Tokens moved immediately, without a lock
+    Transfer {
+        sender_id: AccountId,
+        receiver_id: AccountId,
+        amount: U128,
+    },
+    /// Tokens moved to the receiver and locked until the promise chain completes
+    TransferStarted {
+        transfer_id: TransferId,
+        sender_id: AccountId,
+        receiver_id: AccountId,
+        amount: U128,
+        parent_id: Option<TransferId>,
+    },
+    TransferFinalised {
+        transfer_id: TransferId,
+        sender_id: AccountId,
+        receiver_id: AccountId,
+        amount: U128,
+        refunded: U128,
+    },
+    TransferRolledBack {
+        transfer_id: TransferId,
+        sender_id: AccountId,
+        receiver_id: AccountId,
+        amount: U128,
+    },
+    /// Balance of `owner_id` now votes with `delegate_id`
+    DelegateChanged {
+        owner_id: AccountId,
+        delegate_id: AccountId,
+    },
+    MetadataUpdated {
+        metadata: Metadata,
+    },
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+struct EventLog<'a> {
+    standard: &'static str,
+    version: &'static str,
+    #[serde(flatten)]
+    event: &'a TokenEvent,
+}
+
+impl TokenEvent {
+
+    /// The log line for this event
+    pub fn to_log_string(&self) -> String {
+        let log = EventLog {
+            standard: EVENT_STANDARD,
+            version: EVENT_VERSION,
+            event: self,
+        };
+        format!("{}{}", EVENT_JSON_PREFIX, serde_json::to_string(&log).unwrap())
+    }
+
+    pub fn emit(&self) {
+        env::log(self.to_log_string().as_bytes());
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_event_format() {
+        let event = TokenEvent::TransferStarted {
+            transfer_id: 3,
+            sender_id: "bob.near".to_string(),
+            receiver_id: "carol.near".to_string(),
+            amount: 100.into(),
+            parent_id: None,
+        };
+        assert_eq!(
+            event.to_log_string(),
+            "EVENT_JSON:{\"standard\":\"nep9000\",\"version\":\"1.0.0\",\"event\":\"transfer_started\",\
+            \"data\":{\"transfer_id\":3,\"sender_id\":\"bob.near\",\"receiver_id\":\"carol.near\",\"amount\":\"100\",\"parent_id\":null}}"
+        );
+    }
+}
@@ -5,9 +5,23 @@
 use near_sdk::wee_alloc;
 
 pub mod token;
+pub mod checkpoints;
+pub mod delegation;
+pub mod errors;
+pub mod events;
+pub mod metadata;
+pub mod migration;
+pub mod nep141;
+pub mod nep21;
+pub mod storage;
 pub mod receiver;
 pub mod utils;
 
+#[cfg(test)]
+mod test_utils;
 
+
+// Only the deployed contract uses wee_alloc, so other crates can link the token as a library
+#[cfg(target_arch = "wasm32")]
 #[global_allocator]
 static ALLOC: wee_alloc::WeeAlloc = wee_alloc::WeeAlloc::INIT;
@@ -0,0 +1,138 @@
+use near_sdk::borsh::{ self, BorshDeserialize, BorshSerialize };
+use near_sdk::serde::{ Serialize, Deserialize };
+use near_sdk::json_types::Base64VecU8;
+
+use crate::errors::TokenError;
+
+/// Icons are stored on chain, so keep them small
+pub const MAX_ICON_LENGTH: usize = 2048;
+
+/// metadata_hash is sha256 of the metadata file
+pub const METADATA_HASH_LENGTH: usize = 32;
+
+/*
+ * Information about the token.
+ *
+ * We hold the name, symbol and homepage readibly available on chain, but other information must be
+ * from the JSON data. This way we do not bloat the chain size and also make upgrading the information
+ * somewhat easier.
+ *
+ * All metadata fields are optional, except decimals.
+ */
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Default)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Metadata {
+
+    // Name of the token
+    pub name: String,
+
+    // Symbol of the token
+    pub symbol: String,
+
+    // URL to the human readable page about the token
+    pub web_link: String,
+
+    // URL to the metadata file with more information about the token, like different icon sets
+    pub metadata_link: String,
+
+    // How many decimal places wallets should use when displaying balances
+    pub decimals: u8,
+
+    // Small icon as a data URL, e.g. data:image/svg+xml,...
+    pub icon: Option<String>,
+
+    // sha256 of the file at metadata_link, so clients can verify they got the right file
+    pub metadata_hash: Option<Base64VecU8>,
+}
+
+impl Metadata {
+
+    pub fn validate(&self) -> Result<(), TokenError> {
+        if let Some(icon) = &self.icon {
+            if !icon.starts_with("data:") {
+                return Err(TokenError::IconNotDataUrl);
+            }
+            if icon.len() > MAX_ICON_LENGTH {
+                return Err(TokenError::IconTooLong { max_length: MAX_ICON_LENGTH });
+            }
+        }
+        if let Some(metadata_hash) = &self.metadata_hash {
+            if metadata_hash.0.len() != METADATA_HASH_LENGTH {
+                return Err(TokenError::InvalidMetadataHash { length: METADATA_HASH_LENGTH });
+            }
+            if self.metadata_link.is_empty() {
+                return Err(TokenError::MetadataHashWithoutLink);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Metadata before decimals, icon and metadata hash were added, the layout of the first contract version
+#[derive(BorshDeserialize, BorshSerialize, Clone)]
+pub struct MetadataV1 {
+    pub name: String,
+    pub symbol: String,
+    pub web_link: String,
+    pub metadata_link: String,
+}
+
+/**
+ * Metadata as it is stored in the contract state.
+ *
+ * New fields go to a new version, so the metadata written by older contract
+ * versions still deserialises after a contract upgrade. The first contract version
+ * stored MetadataV1 without the version tag, its state is converted with Token::migrate().
+ */
+#[derive(BorshDeserialize, BorshSerialize)]
+pub enum VersionedMetadata {
+    V1(MetadataV1),
+    V2(Metadata),
+}
+
+impl VersionedMetadata {
+
+    /// Get the metadata in the latest layout
+    pub fn get(&self) -> Metadata {
+        match self {
+            VersionedMetadata::V1(metadata) => Metadata {
+                name: metadata.name.clone(),
+                symbol: metadata.symbol.clone(),
+                web_link: metadata.web_link.clone(),
+                metadata_link: metadata.metadata_link.clone(),
+                decimals: 0,
+                icon: None,
+                metadata_hash: None,
+            },
+            VersionedMetadata::V2(metadata) => metadata.clone(),
+        }
+    }
+}
+
+impl From<Metadata> for VersionedMetadata {
+
+    fn from(metadata: Metadata) -> Self {
+        VersionedMetadata::V2(metadata)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_v1_metadata_upgrades() {
+        let stored = VersionedMetadata::V1(MetadataV1 {
+            name: String::from("Old"),
+            symbol: String::from("OLD"),
+            web_link: String::from(""),
+            metadata_link: String::from(""),
+        });
+        let bytes = stored.try_to_vec().unwrap();
+        let metadata = VersionedMetadata::try_from_slice(&bytes).unwrap().get();
+        assert_eq!(metadata.symbol, "OLD");
+        assert_eq!(metadata.decimals, 0);
+        assert!(metadata.icon.is_none());
+    }
+}
@@ -0,0 +1,183 @@
+/*
+ * Upgrading a token deployed with the first version of this contract.
+ *
+ * The first version stored the metadata without a version tag and had a much smaller ledger,
+ * so its state does not deserialise as Token. Deploy the new code and call migrate()
+ * from the token account in the same transaction.
+ * The first version had no owner, so migrate() is given one:
+ *
+ *     near deploy --accountId token.near --wasmFile token.wasm --initFunction migrate --initArgs '{"owner_id": "owner.near"}'
+ *
+ * The first version kept the balances in a LookupMap, which cannot be iterated.
+ * The owner then moves them to the new ledger with migrate_balances(), giving the holder accounts
+ * in batches, e.g. from the indexed transfer history. Holders are registered without a storage deposit,
+ * the contract pays for their storage as it did before.
+ */
+
+use near_sdk::borsh::{ self, BorshDeserialize, BorshSerialize };
+use near_sdk::collections::LookupMap;
+use near_sdk::{ env, near_bindgen, AccountId, Balance };
+
+use crate::errors::UnwrapOrPanic;
+use crate::metadata::{ MetadataV1, VersionedMetadata };
+use crate::token::{ Ledger, Token, DEFAULT_CONFIRMATION_DEPOSIT, DEFAULT_LOCK_EXPIRY_BLOCKS };
+
+/// Ledger of the first contract version, its balances and locks are under the prefixes b"bal" and b"lck"
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct LedgerV1 {
+    pub balances: LookupMap<AccountId, Balance>,
+    pub locked_balances: LookupMap<AccountId, Balance>,
+    pub total_supply: Balance,
+    pub rollbacks: u64,
+}
+
+/// Contract state of the first contract version
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct TokenV1 {
+    pub ledger: LedgerV1,
+    pub metadata: MetadataV1,
+}
+
+#[near_bindgen]
+impl Token {
+
+    /**
+     * Convert the state written by the first contract version.
+     *
+     * The metadata is kept. The first version had no owner, so `owner_id` becomes the owner.
+     * Only the token account can call this, so the call must be batched with the deployment of the new code.
+     */
+    #[init]
+    pub fn migrate(owner_id: AccountId) -> Self {
+        assert_eq!(env::predecessor_account_id(), env::current_account_id(), "Only the token account can migrate the state");
+        assert!(env::is_valid_account_id(owner_id.as_bytes()), "Owner's account ID is invalid");
+        let old: TokenV1 = env::state_read().expect("No state to migrate");
+
+        let mut token = Self {
+            ledger: Ledger::new(None, DEFAULT_LOCK_EXPIRY_BLOCKS),
+            metadata: VersionedMetadata::V1(old.metadata),
+            owner_id: owner_id.clone(),
+            minters: vec![],
+            confirmation_deposit: DEFAULT_CONFIRMATION_DEPOSIT,
+        };
+        token.ledger.rollbacks = old.ledger.rollbacks;
+        token.ledger.measure_account_storage_usage();
+        token.ledger.register_sponsored(&owner_id, 0).unwrap_or_panic();
+        token.ledger.set_total_supply(old.ledger.total_supply);
+        token
+    }
+
+    /**
+     * Move the balances of `account_ids` from the ledger of the first contract version.
+     *
+     * Accounts that have already been moved, or never held tokens, are skipped.
+     * Returns how many balances were moved. Only the owner can do this, and it pays for the balance history.
+     */
+    #[payable]
+    pub fn migrate_balances(&mut self, account_ids: Vec<AccountId>) -> u64 {
+        self.assert_owner();
+        self.take_confirmation_deposit();
+        let mut moved = 0;
+        for account_id in account_ids {
+            let key = v1_storage_key(b"bal", &account_id);
+            let balance = match env::storage_read(&key) {
+                Some(x) => Balance::try_from_slice(&x).unwrap(),
+                None => continue,
+            };
+            env::storage_remove(&key);
+
+            // The old per-account lock shares the key of the new lock total. Transfers of the first
+            // version can no longer complete, so the lock is dropped unless the account already has new transfers.
+            if !self.ledger.pending_transfers.contains_key(&account_id) {
+                self.ledger.locked_balances.remove(&account_id);
+            }
+            if !self.ledger.is_registered(&account_id) {
+                self.ledger.register_sponsored(&account_id, 0).unwrap_or_panic();
+            }
+            self.ledger.set_balance(&account_id, balance, &self.owner_id).unwrap_or_panic();
+            moved += 1;
+        }
+        moved
+    }
+}
+
+/// Storage key of `account_id` in a LookupMap of the first contract version
+fn v1_storage_key(prefix: &[u8], account_id: &AccountId) -> Vec<u8> {
+    let mut key = prefix.to_vec();
+    key.extend(account_id.try_to_vec().unwrap());
+    key
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nep9000_testing::{ alice, bob, carol, set_context, ContextBuilder };
+
+    /// State as the first contract version wrote it, with 600 tokens of bob and 400 of carol
+    fn write_v1_state() {
+        let mut ledger = LedgerV1 {
+            balances: LookupMap::new(b"bal".to_vec()),
+            locked_balances: LookupMap::new(b"lck".to_vec()),
+            total_supply: 1_000,
+            rollbacks: 3,
+        };
+        ledger.balances.insert(&bob(), &600);
+        ledger.balances.insert(&carol(), &400);
+        ledger.locked_balances.insert(&carol(), &50);
+
+        let metadata = MetadataV1 {
+            name: String::from("Old token"),
+            symbol: String::from("OLD"),
+            web_link: String::from("https://example.com"),
+            metadata_link: String::from(""),
+        };
+        env::state_write(&TokenV1 { ledger, metadata });
+    }
+
+    #[test]
+    fn test_migrate_v1_state() {
+        set_context(ContextBuilder::new().predecessor_account_id(alice()).build());
+        write_v1_state();
+
+        let contract = Token::migrate(bob());
+        let metadata = contract.get_metadata();
+        assert_eq!(metadata.name, "Old token");
+        assert_eq!(metadata.symbol, "OLD");
+        assert_eq!(metadata.web_link, "https://example.com");
+        assert_eq!(metadata.decimals, 0);
+        assert_eq!(contract.get_owner(), bob());
+        assert_eq!(contract.get_total_supply(), 1_000);
+        assert_eq!(contract.get_rollback_count(), 3);
+        assert!(contract.ledger.is_registered(&bob()));
+    }
+
+    #[test]
+    fn test_migrate_v1_balances() {
+        set_context(ContextBuilder::new().predecessor_account_id(alice()).build());
+        write_v1_state();
+        let mut contract = Token::migrate(bob());
+        assert_eq!(contract.get_holder_count(), 0);
+
+        set_context(ContextBuilder::new().predecessor_account_id(bob()).attached_deposit(1).build());
+        assert_eq!(contract.migrate_balances(vec![bob(), carol(), alice()]), 2);
+        assert_eq!(contract.get_balance(bob()), 600);
+        assert_eq!(contract.get_balance(carol()), 400);
+        assert_eq!(contract.get_locked_balance(carol()), 0);
+        assert_eq!(contract.get_holder_count(), 2);
+        assert!(contract.ledger.is_registered(&carol()));
+        assert!(!contract.ledger.is_registered(&alice()));
+
+        // Moving again does nothing
+        assert_eq!(contract.migrate_balances(vec![bob()]), 0);
+        assert_eq!(contract.get_balance(bob()), 600);
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the token account can migrate the state")]
+    fn test_only_token_account_migrates() {
+        set_context(ContextBuilder::new().predecessor_account_id(bob()).build());
+        write_v1_state();
+        Token::migrate(bob());
+    }
+}
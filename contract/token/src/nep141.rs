@@ -0,0 +1,233 @@
+/*
+ * NEP-141 compatibility layer.
+ *
+ * Lets wallets and exchanges that speak the standard NEAR fungible token interface
+ * use Advanced Fungible tokens. ft_transfer() is a plain balance move.
+ * ft_transfer_call() locks the tokens like send() does, and ft_resolve_transfer()
+ * finalises the part the receiver used and rolls back the rest.
+ *
+ * https://nomicon.io/Standards/FungibleToken/Core.html
+ */
+
+use near_sdk::serde_json::{ self, json };
+use near_sdk::serde::{ Serialize, Deserialize };
+use near_sdk::{ env, near_bindgen, AccountId };
+use near_sdk::json_types::{ U128, Base64VecU8 };
+
+use crate::errors::UnwrapOrPanic;
+use crate::receiver::{ FtOnTransferArgs, ReceiverResult, FT_ON_TRANSFER_METHOD, REJECT_RECEIVER_FAILED };
+use crate::token::{ Token, TransferId, TransferStatus, SINGLE_CALL_GAS };
+use crate::utils::get_promise_result;
+
+pub const FT_METADATA_SPEC: &str = "ft-1.0.0";
+
+/// Token metadata in the NEP-148 format
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct FungibleTokenMetadata {
+    pub spec: String,
+    pub name: String,
+    pub symbol: String,
+    pub icon: Option<String>,
+    pub reference: Option<String>,
+    pub reference_hash: Option<Base64VecU8>,
+    pub decimals: u8,
+}
+
+#[near_bindgen]
+impl Token {
+
+    /// Move tokens to `receiver_id` without notifying it
+    #[payable]
+    pub fn ft_transfer(&mut self, receiver_id: AccountId, amount: U128, memo: Option<String>) {
+        self.take_confirmation_deposit();
+        if let Some(memo) = memo {
+            env::log(format!("Memo: {}", memo).as_bytes());
+        }
+        self.ledger.transfer(env::predecessor_account_id(), receiver_id, amount.into()).unwrap_or_panic();
+    }
+
+    /**
+     * Move tokens to `receiver_id` and call its ft_on_transfer().
+     *
+     * The tokens stay locked until ft_resolve_transfer(). The promise chain returns
+     * how many tokens the receiver used.
+     */
+    #[payable]
+    pub fn ft_transfer_call(&mut self, receiver_id: AccountId, amount: U128, memo: Option<String>, msg: String) {
+        self.take_confirmation_deposit();
+        if let Some(memo) = memo {
+            env::log(format!("Memo: {}", memo).as_bytes());
+        }
+
+        let sender_id = env::predecessor_account_id();
+        let transfer_id = self.ledger.lock(sender_id.clone(), receiver_id.clone(), amount.into()).unwrap_or_panic();
+
+        let promise0 = env::promise_create(
+            receiver_id.clone(),
+            FT_ON_TRANSFER_METHOD.as_bytes(),
+            &serde_json::to_vec(&FtOnTransferArgs { sender_id: sender_id.clone(), amount: amount.into(), msg }).unwrap(),
+            0,
+            SINGLE_CALL_GAS/3,
+        );
+
+        let promise1 = env::promise_then(
+            promise0,
+            env::current_account_id(),
+            b"ft_resolve_transfer",
+            json!({
+                "sender_id": sender_id,
+                "receiver_id": receiver_id,
+                "amount": amount,
+                "transfer_id": transfer_id,
+            }).to_string().as_bytes(),
+            0,
+            SINGLE_CALL_GAS/10,
+        );
+
+        env::promise_return(promise1);
+    }
+
+    /**
+     * Receiver has processed ft_on_transfer().
+     *
+     * The receiver returned how many tokens it did not use. These are refunded to the sender
+     * and the rest is finalised. If the receiver failed, the whole transfer is rolled back.
+     * Returns how many tokens the receiver kept.
+     */
+    pub fn ft_resolve_transfer(&mut self, sender_id: AccountId, receiver_id: AccountId, amount: U128, transfer_id: TransferId) -> U128 {
+        // Only callable by self
+        assert_eq!(env::current_account_id(), env::predecessor_account_id());
+
+        let amount: u128 = amount.into();
+
+        if self.ledger.get_transfer(transfer_id).unwrap_or_panic().status != TransferStatus::Pending {
+            env::log(format!("Transfer {} was already reclaimed", transfer_id).as_bytes());
+            return 0.into();
+        }
+
+        let unused = match get_promise_result::<U128>() {
+            Some(Ok(unused)) => std::cmp::min(unused.0, amount),
+            Some(Err(_)) | None => {
+                self.ledger.rollback(transfer_id).unwrap_or_panic();
+                self.ledger.set_transfer_result(transfer_id, ReceiverResult::Rejected {
+                    code: REJECT_RECEIVER_FAILED,
+                    reason: String::from("ft_on_transfer failed"),
+                }).unwrap_or_panic();
+                return 0.into();
+            },
+        };
+
+        let amount_used = amount - unused;
+        if unused == 0 {
+            self.ledger.finalise(transfer_id).unwrap_or_panic();
+            self.ledger.set_transfer_result(transfer_id, ReceiverResult::Accepted).unwrap_or_panic();
+        } else {
+            self.ledger.finalise_partial(transfer_id, amount_used).unwrap_or_panic();
+            self.ledger.set_transfer_result(transfer_id, ReceiverResult::AcceptedPartial { amount_used: amount_used.into() }).unwrap_or_panic();
+        }
+
+        amount_used.into()
+    }
+
+    pub fn ft_total_supply(&self) -> U128 {
+        self.ledger.total_supply.into()
+    }
+
+    pub fn ft_balance_of(&self, account_id: AccountId) -> U128 {
+        self.ledger.get_balance(&account_id).into()
+    }
+
+    pub fn ft_metadata(&self) -> FungibleTokenMetadata {
+        let metadata = self.metadata.get();
+        let reference = if metadata.metadata_link.is_empty() {
+            None
+        } else {
+            Some(metadata.metadata_link)
+        };
+
+        FungibleTokenMetadata {
+            spec: String::from(FT_METADATA_SPEC),
+            name: metadata.name,
+            symbol: metadata.symbol,
+            icon: metadata.icon,
+            reference,
+            reference_hash: metadata.metadata_hash,
+            decimals: metadata.decimals,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_sdk::PromiseResult;
+    use nep9000_testing::{ alice, bob, carol, get_deposit_context, set_callback_context, set_context };
+
+    use crate::test_utils::{ register_accounts, STAKE };
+
+    #[test]
+    fn test_ft_transfer() {
+        set_context(get_deposit_context(bob(), 1));
+        let mut contract = Token::new(bob(), 1_000u128, None, None, None);
+        register_accounts(&mut contract);
+        contract.ft_transfer(carol(), 100.into(), None);
+        assert_eq!(contract.ft_balance_of(carol()), 100.into());
+        assert_eq!(contract.ft_balance_of(bob()), 900.into());
+        assert_eq!(contract.ft_total_supply(), 1_000.into());
+    }
+
+    #[test]
+    #[should_panic(expected = "Requires attached deposit of exactly 1 yoctoNEAR")]
+    fn test_ft_transfer_requires_one_yocto() {
+        set_context(get_deposit_context(bob(), 0));
+        let mut contract = Token::new(bob(), 1_000u128, None, None, None);
+        register_accounts(&mut contract);
+        contract.ft_transfer(carol(), 100.into(), None);
+    }
+
+    #[test]
+    fn test_ft_transfer_takes_confirmation_deposit() {
+        set_context(get_deposit_context(bob(), 1));
+        let mut contract = Token::new(bob(), 1_000u128, None, None, None);
+        register_accounts(&mut contract);
+        contract.set_confirmation_deposit(5.into());
+
+        set_context(get_deposit_context(bob(), 5));
+        contract.ft_transfer(carol(), 100.into(), None);
+        assert_eq!(contract.ledger.get_storage_deposit(&bob()), Some(STAKE + 6));
+    }
+
+    #[test]
+    fn test_ft_transfer_call_refunds_unused() {
+        set_context(get_deposit_context(bob(), 1));
+        let mut contract = Token::new(bob(), 1_000u128, None, None, None);
+        register_accounts(&mut contract);
+        contract.ft_transfer_call(carol(), 100.into(), None, String::from("swap"));
+        assert_eq!(contract.get_locked_balance(carol()), 100);
+
+        set_callback_context(get_deposit_context(alice(), 0), PromiseResult::Successful(b"\"30\"".to_vec()));
+        let used = contract.ft_resolve_transfer(bob(), carol(), 100.into(), 0);
+
+        assert_eq!(used, 70.into());
+        assert_eq!(contract.ft_balance_of(bob()), 930.into());
+        assert_eq!(contract.ft_balance_of(carol()), 70.into());
+        assert_eq!(contract.get_locked_balance(carol()), 0);
+    }
+
+    #[test]
+    fn test_ft_transfer_call_rolls_back_on_failure() {
+        set_context(get_deposit_context(bob(), 1));
+        let mut contract = Token::new(bob(), 1_000u128, None, None, None);
+        register_accounts(&mut contract);
+        contract.ft_transfer_call(carol(), 100.into(), None, String::from(""));
+
+        set_callback_context(get_deposit_context(alice(), 0), PromiseResult::Failed);
+        let used = contract.ft_resolve_transfer(bob(), carol(), 100.into(), 0);
+
+        assert_eq!(used, 0.into());
+        assert_eq!(contract.ft_balance_of(bob()), 1_000.into());
+        assert_eq!(contract.get_rollback_count(), 1);
+    }
+}
@@ -0,0 +1,119 @@
+/*
+ * NEP-21 compatibility layer.
+ *
+ * Older exchange contracts move tokens with approve() and transfer_from() style
+ * allowances instead of send(). Allowances only cover the unlocked balance,
+ * so tokens in transit cannot be spent through them.
+ *
+ * These methods do not require the confirmation deposit, as NEP-21 callers do not attach one.
+ * Any deposit they do attach is added to the storage stake of the caller, or refunded.
+ *
+ * https://github.com/near/NEPs/blob/master/specs/Standards/Tokens/FungibleToken.md
+ */
+
+use near_sdk::{ env, near_bindgen, AccountId };
+use near_sdk::json_types::U128;
+
+use crate::errors::UnwrapOrPanic;
+use crate::token::Token;
+
+#[near_bindgen]
+impl Token {
+
+    /// Allow `escrow_account_id` to move `amount` more tokens of the caller
+    #[payable]
+    pub fn inc_allowance(&mut self, escrow_account_id: AccountId, amount: U128) {
+        self.take_attached_deposit();
+        self.ledger.inc_allowance(&env::predecessor_account_id(), &escrow_account_id, amount.into()).unwrap_or_panic();
+    }
+
+    /// Allow `escrow_account_id` to move `amount` less tokens of the caller
+    #[payable]
+    pub fn dec_allowance(&mut self, escrow_account_id: AccountId, amount: U128) {
+        self.take_attached_deposit();
+        self.ledger.dec_allowance(&env::predecessor_account_id(), &escrow_account_id, amount.into());
+    }
+
+    /// Move tokens of `owner_id` within the allowance the owner gave to the caller
+    #[payable]
+    pub fn transfer_from(&mut self, owner_id: AccountId, new_owner_id: AccountId, amount: U128) {
+        self.take_attached_deposit();
+        self.ledger.transfer_from(&env::predecessor_account_id(), owner_id, new_owner_id, amount.into()).unwrap_or_panic();
+    }
+
+    /// How many tokens `escrow_account_id` can move on behalf of `owner_id`
+    pub fn get_allowance(&self, owner_id: AccountId, escrow_account_id: AccountId) -> U128 {
+        self.ledger.get_allowance(&owner_id, &escrow_account_id).into()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nep9000_testing::{ alice, bob, carol, get_deposit_context, set_context };
+
+    use crate::test_utils::{ register_accounts, STAKE };
+
+    #[test]
+    fn test_transfer_from() {
+        set_context(get_deposit_context(bob(), 0));
+        let mut contract = Token::new(bob(), 1_000u128, None, None, None);
+        register_accounts(&mut contract);
+        contract.inc_allowance(carol(), 300.into());
+        contract.dec_allowance(carol(), 100.into());
+        assert_eq!(contract.get_allowance(bob(), carol()), 200.into());
+
+        set_context(get_deposit_context(carol(), 0));
+        contract.transfer_from(bob(), alice(), 150.into());
+        assert_eq!(contract.get_balance(alice()), 150);
+        assert_eq!(contract.get_balance(bob()), 850);
+        assert_eq!(contract.get_allowance(bob(), carol()), 50.into());
+    }
+
+    #[test]
+    fn test_attached_deposit_is_staked() {
+        set_context(get_deposit_context(bob(), 10));
+        let mut contract = Token::new(bob(), 1_000u128, None, None, None);
+        register_accounts(&mut contract);
+        contract.inc_allowance(carol(), 300.into());
+        assert_eq!(contract.ledger.get_storage_deposit(&bob()), Some(STAKE + 10));
+    }
+
+    #[test]
+    #[should_panic(expected = "Not enough allowance")]
+    fn test_transfer_from_over_allowance() {
+        set_context(get_deposit_context(bob(), 1));
+        let mut contract = Token::new(bob(), 1_000u128, None, None, None);
+        register_accounts(&mut contract);
+        contract.inc_allowance(carol(), 100.into());
+
+        set_context(get_deposit_context(carol(), 1));
+        contract.transfer_from(bob(), alice(), 101.into());
+    }
+
+    #[test]
+    #[should_panic(expected = "Account carol.near is not registered")]
+    fn test_unregistered_cannot_give_allowance() {
+        set_context(get_deposit_context(bob(), 1));
+        let mut contract = Token::new(bob(), 1_000u128, None, None, None);
+
+        set_context(get_deposit_context(carol(), 1));
+        contract.inc_allowance(alice(), 100.into());
+    }
+
+    #[test]
+    #[should_panic(expected = "in tx lock")]
+    fn test_transfer_from_cannot_move_locked() {
+        set_context(get_deposit_context(bob(), 1));
+        let mut contract = Token::new(bob(), 1_000u128, None, None, None);
+        register_accounts(&mut contract);
+        contract.send(carol(), 100, vec![]);
+
+        // Carol received 100 tokens, but they are still in transit
+        set_context(get_deposit_context(carol(), 1));
+        contract.inc_allowance(alice(), 100.into());
+        set_context(get_deposit_context(alice(), 1));
+        contract.transfer_from(carol(), bob(), 100.into());
+    }
+}
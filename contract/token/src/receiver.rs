@@ -1,17 +1,9 @@
-use near_sdk::{ AccountId, Balance, ext_contract };
-use near_sdk::json_types::U128;
-
-/* The smart contract interface for handing incoming token transfers of Advanced Fungible.
- *
+/*
+ * The receiver interface lives in nep9000_interface, so receiver contracts
+ * do not need to depend on the token. Re-exported here for existing users.
  */
-#[ext_contract(ext_token_receiver)]
-pub trait Receiver {
-
-    /// Interface check promise to check if the receiver contract is able to handle Advanced Fungible
-    /// Always return true
-    fn is_receiver(self) -> PromiseOrValue<bool>;
 
-    /// Notified after the balance transfer is complete. Must return true to finalise the transaction.
-    /// TODO: More advanced error code / return value needed
-    fn on_token_received(&mut self, sender_id: AccountId, amount_received: U128, amount_total: U128, message: Vec<u8>) -> PromiseOrValue<bool>;
-}
\ No newline at end of file
+pub use nep9000_interface::{
+    ext_ft_receiver, ext_token_receiver, FtOnTransferArgs, FungibleTokenReceiver, OnTokenReceivedArgs, Receiver, ReceiverResult,
+    FT_ON_TRANSFER_METHOD, IS_RECEIVER_METHOD, ON_TOKEN_RECEIVED_METHOD, REJECT_CODE_CUSTOM, REJECT_INVALID_RESULT, REJECT_RECEIVER_FAILED,
+};
@@ -0,0 +1,346 @@
+/*
+ * Storage staking.
+ *
+ * Accounts must be registered with storage_deposit() before they can hold tokens,
+ * so senders cannot make the token contract pay the storage of arbitrary new accounts.
+ * The minimum deposit covers the balance records. Records that grow with use, like transfers,
+ * allowances and the balance history, are charged to the stake of the account that writes them,
+ * so sending tokens needs a deposit above the minimum. Senders also pay for the history of the receiver.
+ * Accounts registered by the contract itself only get the minimum for free.
+ * The staked deposit is returned by storage_unregister().
+ *
+ * https://nomicon.io/Standards/StorageManagement.html
+ */
+
+use near_sdk::serde::{ Serialize, Deserialize };
+use near_sdk::{ env, near_bindgen, AccountId, Balance, Promise };
+use near_sdk::json_types::U128;
+
+use crate::errors::{ TokenError, UnwrapOrPanic };
+use crate::token::Token;
+
+/// How much an account has staked for storage and how much of it can be withdrawn
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct StorageBalance {
+    pub total: U128,
+    pub available: U128,
+}
+
+/// How much an account needs to stake to be registered
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct StorageBalanceBounds {
+    pub min: U128,
+    pub max: Option<U128>,
+}
+
+#[near_bindgen]
+impl Token {
+
+    /**
+     * Register `account_id`, or the caller if not given, by staking the attached deposit.
+     *
+     * The attached deposit must cover the storage cost of a new account.
+     * Deposits to an already registered account are added to its stake,
+     * unless `registration_only` is set, in which case they are refunded.
+     */
+    #[payable]
+    pub fn storage_deposit(&mut self, account_id: Option<AccountId>, registration_only: Option<bool>) -> StorageBalance {
+        let amount = env::attached_deposit();
+        let account_id = account_id.unwrap_or_else(env::predecessor_account_id);
+        let registration_only = registration_only.unwrap_or(false);
+
+        if self.ledger.is_registered(&account_id) {
+            if registration_only {
+                self.refund_deposit(amount);
+            } else {
+                self.ledger.add_storage_deposit(&account_id, amount).unwrap_or_panic();
+            }
+        } else {
+            let cost = self.ledger.get_storage_cost();
+            if amount < cost {
+                TokenError::RegistrationDeposit { required: cost, attached: amount }.panic();
+            }
+            if registration_only {
+                self.ledger.register(&account_id, cost).unwrap_or_panic();
+                self.refund_deposit(amount - cost);
+            } else {
+                self.ledger.register(&account_id, amount).unwrap_or_panic();
+            }
+        }
+
+        self.storage_balance_of(account_id).unwrap()
+    }
+
+    /**
+     * Withdraw the part of the caller's storage stake that is not needed for its records.
+     *
+     * The confirmation deposit is added to the stake first, so it can be withdrawn in the same call.
+     */
+    #[payable]
+    pub fn storage_withdraw(&mut self, amount: Option<U128>) -> StorageBalance {
+        self.take_confirmation_deposit();
+        let account_id = env::predecessor_account_id();
+        let available = self.storage_balance_of(account_id.clone())
+            .unwrap_or_else(|| TokenError::NotRegistered { account_id: account_id.clone() }.panic())
+            .available;
+        let amount = amount.unwrap_or(available).into();
+        if amount > 0 {
+            self.ledger.withdraw_storage_deposit(&account_id, amount).unwrap_or_panic();
+            Promise::new(account_id.clone()).transfer(amount);
+        }
+        self.storage_balance_of(account_id).unwrap()
+    }
+
+    /**
+     * Remove the caller's account and return its storage stake,
+     * except the part paying for the records that stay, like completed transfers and the balance history.
+     *
+     * Returns false if the account was not registered.
+     * `force` burns the remaining token balance, otherwise the balance must be zero.
+     * The confirmation deposit is returned with the stake.
+     */
+    #[payable]
+    pub fn storage_unregister(&mut self, force: Option<bool>) -> bool {
+        self.take_confirmation_deposit();
+        let account_id = env::predecessor_account_id();
+        if !self.ledger.is_registered(&account_id) {
+            return false;
+        }
+        let deposit = self.ledger.unregister(&account_id, force.unwrap_or(false)).unwrap_or_panic();
+        Promise::new(account_id).transfer(deposit);
+        true
+    }
+
+    /// Returns the storage stake of `account_id`, or null if the account is not registered
+    pub fn storage_balance_of(&self, account_id: AccountId) -> Option<StorageBalance> {
+        let total = self.ledger.get_storage_deposit(&account_id)?;
+        Some(StorageBalance {
+            total: total.into(),
+            available: total.saturating_sub(self.ledger.get_required_storage_deposit(&account_id)).into(),
+        })
+    }
+
+    pub fn storage_balance_bounds(&self) -> StorageBalanceBounds {
+        StorageBalanceBounds {
+            min: self.ledger.get_storage_cost().into(),
+            max: None,
+        }
+    }
+
+    fn refund_deposit(&self, amount: Balance) {
+        if amount > 0 {
+            Promise::new(env::predecessor_account_id()).transfer(amount);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nep9000_testing::{ advance_blocks, alice, bob, carol, current_context, get_deposit_context, set_context, ContextBuilder };
+
+    use crate::test_utils::{ register_accounts, STAKE };
+    use crate::token::{ HISTORY_STORAGE_USAGE, LOCK_STORAGE_USAGE, STORAGE_PRICE_PER_BYTE };
+
+    #[test]
+    fn test_storage_deposit_registers() {
+        set_context(get_deposit_context(bob(), 0));
+        let mut contract = Token::new(bob(), 1_000u128, None, None, None);
+        contract.ledger.add_storage_deposit(&bob(), STAKE).unwrap_or_panic();
+        let cost = contract.storage_balance_bounds().min.0;
+        assert!(cost > 0);
+        assert_eq!(contract.storage_balance_of(carol()), None);
+
+        set_context(get_deposit_context(carol(), cost * 2));
+        let balance = contract.storage_deposit(None, None);
+        assert_eq!(balance.total, (cost * 2).into());
+        assert_eq!(balance.available, cost.into());
+
+        set_context(get_deposit_context(bob(), 1));
+        contract.ft_transfer(carol(), 100.into(), None);
+        assert_eq!(contract.get_balance(carol()), 100);
+    }
+
+    #[test]
+    #[should_panic(expected = "Registering an account needs a deposit")]
+    fn test_storage_deposit_too_small() {
+        set_context(get_deposit_context(bob(), 0));
+        let mut contract = Token::new(bob(), 1_000u128, None, None, None);
+        let cost = contract.storage_balance_bounds().min.0;
+
+        set_context(get_deposit_context(carol(), cost - 1));
+        contract.storage_deposit(None, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Account carol.near is not registered")]
+    fn test_cannot_send_to_unregistered() {
+        set_context(get_deposit_context(bob(), 1));
+        let mut contract = Token::new(bob(), 1_000u128, None, None, None);
+        contract.send(carol(), 100, vec![]);
+    }
+
+    #[test]
+    fn test_storage_unregister() {
+        set_context(get_deposit_context(bob(), 0));
+        let mut contract = Token::new(bob(), 1_000u128, None, None, None);
+        contract.ledger.add_storage_deposit(&bob(), STAKE).unwrap_or_panic();
+        let cost = contract.storage_balance_bounds().min.0;
+
+        set_context(get_deposit_context(carol(), cost));
+        contract.storage_deposit(None, Some(true));
+        set_context(get_deposit_context(bob(), 1));
+        contract.ft_transfer(carol(), 100.into(), None);
+
+        set_context(get_deposit_context(carol(), 1));
+        assert!(contract.storage_unregister(Some(true)));
+        assert_eq!(contract.storage_balance_of(carol()), None);
+        assert_eq!(contract.get_total_supply(), 900);
+        assert!(!contract.storage_unregister(None));
+    }
+
+    #[test]
+    #[should_panic(expected = "Cannot unregister an account with pending transfers")]
+    fn test_cannot_unregister_with_pending_transfers() {
+        set_context(get_deposit_context(bob(), 1));
+        let mut contract = Token::new(bob(), 1_000u128, None, None, None);
+        register_accounts(&mut contract);
+        contract.send(carol(), 100, vec![]);
+
+        set_context(get_deposit_context(carol(), 1));
+        contract.storage_unregister(Some(true));
+    }
+
+    /// Token owned by bob, with carol holding 500 tokens and staking `extra_bytes` more than the minimum
+    fn setup_carol(extra_bytes: u128) -> Token {
+        set_context(get_deposit_context(bob(), 1));
+        let mut contract = Token::new(bob(), 1_000u128, None, None, None);
+        contract.ledger.add_storage_deposit(&bob(), STAKE).unwrap_or_panic();
+        let deposit = contract.storage_balance_bounds().min.0 + extra_bytes * STORAGE_PRICE_PER_BYTE;
+        set_context(get_deposit_context(carol(), deposit));
+        contract.storage_deposit(None, None);
+        set_context(get_deposit_context(bob(), 1));
+        contract.ft_transfer(carol(), 500.into(), None);
+        contract
+    }
+
+    #[test]
+    fn test_transfers_are_charged_to_the_sender() {
+        let mut contract = setup_carol(10_000);
+        let available = contract.storage_balance_of(carol()).unwrap().available.0;
+        let receiver_required = contract.ledger.get_required_storage_deposit(&bob());
+
+        set_context(get_deposit_context(carol(), 1));
+        let transfer_id = contract.send(bob(), 100, vec![]);
+        let pending_available = contract.storage_balance_of(carol()).unwrap().available.0;
+        assert!(pending_available < available);
+
+        // The pending entries are credited back, the transfer record stays paid
+        contract.ledger.finalise(transfer_id).unwrap_or_panic();
+        let final_available = contract.storage_balance_of(carol()).unwrap().available.0;
+        assert!(final_available > pending_available);
+        assert!(final_available < available);
+
+        // The sender also pays for the balance history of the receiver
+        assert_eq!(contract.ledger.get_required_storage_deposit(&bob()), receiver_required);
+    }
+
+    #[test]
+    #[should_panic(expected = "Records of carol.near need a storage deposit of")]
+    fn test_send_needs_storage_deposit() {
+        let mut contract = setup_carol(0);
+        set_context(get_deposit_context(carol(), 1));
+        contract.send(bob(), 100, vec![]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Records of carol.near need a storage deposit of")]
+    fn test_sponsored_send_needs_storage_deposit() {
+        set_context(get_deposit_context(bob(), 1));
+        let mut contract = Token::new(bob(), 1_000u128, None, None, None);
+        contract.ledger.add_storage_deposit(&bob(), STAKE).unwrap_or_panic();
+        contract.ledger.register_sponsored(&carol(), 0).unwrap_or_panic();
+        contract.ft_transfer(carol(), 500.into(), None);
+        assert_eq!(contract.storage_balance_of(carol()).unwrap().available, 0.into());
+
+        set_context(get_deposit_context(carol(), 1));
+        contract.ft_transfer(bob(), 100.into(), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Records of carol.near need a storage deposit of")]
+    fn test_allowance_needs_storage_deposit() {
+        let mut contract = setup_carol(0);
+        set_context(get_deposit_context(carol(), 1));
+        contract.inc_allowance(alice(), 100.into());
+    }
+
+    #[test]
+    fn test_cannot_withdraw_storage_of_records() {
+        let mut contract = setup_carol(10_000);
+        set_context(get_deposit_context(carol(), 1));
+        contract.inc_allowance(alice(), 100.into());
+        let balance = contract.storage_balance_of(carol()).unwrap();
+        let required = balance.total.0 - balance.available.0;
+        assert!(required > contract.storage_balance_bounds().min.0);
+
+        contract.storage_withdraw(None);
+        assert_eq!(contract.storage_balance_of(carol()).unwrap().total.0, required);
+        contract.ledger.check_storage_deposit(&carol()).unwrap_or_panic();
+    }
+
+    #[test]
+    fn test_unregister_keeps_history_and_record_storage() {
+        let mut contract = setup_carol(10_000);
+        let block = current_context().block_index;
+        assert_eq!(contract.get_balance_at(carol(), block), 500);
+
+        set_context(get_deposit_context(carol(), 1));
+        advance_blocks(1);
+        let transfer_id = contract.send(bob(), 100, vec![]);
+        contract.ledger.finalise(transfer_id).unwrap_or_panic();
+        let deposit = contract.storage_balance_of(carol()).unwrap().total.0;
+        let charged = contract.ledger.get_required_storage_deposit(&carol()) - contract.ledger.get_storage_cost();
+
+        let refund = contract.ledger.unregister(&carol(), true).unwrap_or_panic();
+        assert_eq!(contract.get_balance_at(carol(), block), 500);
+        assert_eq!(contract.get_balance_at(carol(), current_context().block_index), 0);
+
+        // The storage of the history and the transfer record is kept
+        assert!(contract.get_transfer(transfer_id).is_some());
+        assert!(refund > 0);
+        assert!(refund <= deposit - charged);
+    }
+
+    #[test]
+    fn test_storage_estimates_cover_longest_account_ids() {
+        set_context(get_deposit_context(bob(), 1));
+        let mut contract = Token::new(bob(), 1_000u128, None, None, None);
+        contract.ledger.add_storage_deposit(&bob(), STAKE).unwrap_or_panic();
+        let sender = "s".repeat(64);
+        let receiver = "r".repeat(64);
+        let delegate = "d".repeat(64);
+        for account_id in &[&sender, &receiver, &delegate] {
+            contract.ledger.register(account_id, 100 * 10u128.pow(24)).unwrap_or_panic();
+        }
+        contract.ledger.transfer(bob(), sender.clone(), 500).unwrap_or_panic();
+        contract.ledger.delegate(&sender, &delegate).unwrap_or_panic();
+        contract.ledger.delegate(&receiver, &delegate).unwrap_or_panic();
+
+        // A new block adds a checkpoint, the first pending transfers add the pending sets
+        advance_blocks(1);
+        let usage = contract.ledger.storage_usages.get(&sender).unwrap();
+        contract.ledger.lock(sender.clone(), receiver.clone(), 100).unwrap_or_panic();
+        let lock_usage = contract.ledger.storage_usages.get(&sender).unwrap() - usage;
+        assert!(lock_usage <= LOCK_STORAGE_USAGE, "Lock took {} bytes", lock_usage);
+
+        advance_blocks(1);
+        let usage = contract.ledger.storage_usages.get(&sender).unwrap();
+        contract.ledger.transfer(sender.clone(), receiver.clone(), 100).unwrap_or_panic();
+        let transfer_usage = contract.ledger.storage_usages.get(&sender).unwrap() - usage;
+        assert!(transfer_usage <= 2 * HISTORY_STORAGE_USAGE, "Transfer took {} bytes", transfer_usage);
+    }
+}
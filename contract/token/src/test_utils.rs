@@ -0,0 +1,22 @@
+/*
+ * Fixtures shared by the unit tests of the token modules.
+ *
+ * The mocked blockchain fixtures that do not need the Token type live in nep9000_testing.
+ */
+
+use near_sdk::Balance;
+use nep9000_testing::{ alice, bob, carol };
+
+use crate::errors::UnwrapOrPanic;
+use crate::token::Token;
+
+/// Storage stake of the test accounts, enough for the records of any test
+pub const STAKE: Balance = 1_000_000_000_000_000_000_000_000;
+
+/// Register the test accounts, the owner bob is registered by new().
+/// Each account stakes for the history its transfers write.
+pub fn register_accounts(contract: &mut Token) {
+    contract.ledger.add_storage_deposit(&bob(), STAKE).unwrap_or_panic();
+    contract.ledger.register_sponsored(&alice(), STAKE).unwrap_or_panic();
+    contract.ledger.register_sponsored(&carol(), STAKE).unwrap_or_panic();
+}
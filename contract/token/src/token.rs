@@ -1,29 +1,192 @@
-/**
+/*
  * An advanced fungible token implementation.
  *
  */
 
 use near_sdk::serde_json::{self, json};
 use near_sdk::borsh::{ self, BorshDeserialize, BorshSerialize};
-use near_sdk::{ env, near_bindgen, ext_contract, AccountId, Balance, Promise, StorageUsage};
-use near_sdk::collections::LookupMap;
+use near_sdk::{ env, near_bindgen, ext_contract, AccountId, Balance, BlockHeight, Promise, StorageUsage};
+use near_sdk::collections::{ LookupMap, LookupSet, UnorderedMap, UnorderedSet, Vector };
 use near_sdk::json_types::U128;
+use near_sdk::serde::{ Serialize, Deserialize };
 
-use crate::receiver::{ ext_token_receiver };
-use crate::utils::{ is_promise_success };
+use crate::checkpoints::{ AccountCheckpoints, Checkpoint, write_checkpoint, value_at };
+use crate::delegation::Delegation;
+use crate::errors::{ TokenError, UnwrapOrPanic };
+use crate::events::TokenEvent;
+use crate::metadata::{ Metadata, VersionedMetadata };
+use crate::receiver::{ OnTokenReceivedArgs, ReceiverResult, IS_RECEIVER_METHOD, ON_TOKEN_RECEIVED_METHOD, REJECT_RECEIVER_FAILED, REJECT_INVALID_RESULT };
+use crate::utils::{ is_promise_success, get_promise_result };
 
 
 // TODO: All gas stipends are more or less random - check througfully
-const SINGLE_CALL_GAS: u64 = 200000000000000;
+pub const SINGLE_CALL_GAS: u64 = 200000000000000;
+
+// Gas for each send_batch() recipient: creating its two promises,
+// and the is_receiver() and handle_receiver() calls. 70 Tgas in total.
+// handle_receiver() passes on what it does not need itself, 10 Tgas for both on_token_received() and handle_token_received().
+pub const BATCH_PROMISE_GAS: u64 = 25000000000000;
+pub const BATCH_CHECK_GAS: u64 = 5000000000000;
+pub const BATCH_HANDLE_GAS: u64 = 40000000000000;
+
+// Gas handle_receiver() keeps for itself, mostly for creating its promises.
+// The rest is split between on_token_received() and handle_token_received().
+const HANDLE_RECEIVER_GAS: u64 = 20000000000000;
+
+// Gas for the is_receiver() check when send() or a forward starts a promise chain
+pub const RECEIVER_CHECK_GAS: u64 = 5000000000000;
+
+// Gas a call keeps for itself when it passes the rest of its gas on to handle_receiver().
+// Creating the two promises of the chain costs about 21 Tgas, and the call still has to finish.
+const NOTIFY_RESERVE_GAS: u64 = 30000000000000;
+
+// Gas send() and a forward need at least: the reserve, the receiver check,
+// and as much for handle_receiver() as a send_batch() recipient gets. 75 Tgas in total.
+pub const NOTIFY_GAS: u64 = NOTIFY_RESERVE_GAS + RECEIVER_CHECK_GAS + BATCH_HANDLE_GAS;
+
+// Gas for each pay_batch() recipient, whose transfer is finalised at once
+pub const BATCH_PAYOUT_GAS: u64 = 5000000000000;
+
+// How many blocks a promise chain can keep the balance locked before anyone can reclaim it
+pub const DEFAULT_LOCK_EXPIRY_BLOCKS: BlockHeight = 1000;
+
+// How many yoctoNEAR state changing calls need to attach by default.
+// Function call access keys cannot attach deposits, so this proves the call was made with a full access key.
+pub const DEFAULT_CONFIRMATION_DEPOSIT: Balance = 1;
+
+// Price of one byte of contract storage, 1 NEAR per 100 kb
+pub const STORAGE_PRICE_PER_BYTE: Balance = 10_000_000_000_000_000_000;
+
+// The longest possible account id, used to measure the storage of one account
+const MAX_ACCOUNT_ID_LENGTH: usize = 64;
+
+/// Storage prefix of the pending transfer sets, followed by the account id
+const PENDING_SET_PREFIX: &[u8] = b"pns";
+
+// Upper bound of the bytes one transfer record and the pending entries of its sender and receiver take
+// with the longest account ids. The stake of the sender is checked against this before anything is written.
+pub const TRANSFER_STORAGE_USAGE: StorageUsage = 1300;
+
+// Upper bound of the bytes one balance change adds to the balance history and the vote history of the delegate
+pub const HISTORY_STORAGE_USAGE: StorageUsage = 700;
+
+// Upper bound of the bytes a locked transfer charges to its sender: the history of both accounts and the transfer record
+pub const LOCK_STORAGE_USAGE: StorageUsage = 2 * HISTORY_STORAGE_USAGE + TRANSFER_STORAGE_USAGE;
+
+/// Identifies one send() promise chain
+pub type TransferId = u64;
+
+/// Where a send() promise chain is
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub enum TransferStatus {
+
+    // Promise chain is in progress and the amount is locked
+    Pending,
+
+    // Receiver accepted the tokens
+    Finalised,
+
+    // Receiver failed and the tokens were returned to the sender
+    RolledBack,
+}
+
+/**
+ * A lock record for one send() promise chain.
+ *
+ * The transferred amount is credited to the receiver immediately,
+ * but it cannot be spent until the promise chain has been finalised.
+ * Records are kept after the chain completes, so the outcome can be queried later.
+ */
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct Transfer {
+
+    // Who sent the tokens
+    pub sender_id: AccountId,
+
+    // Who received the tokens
+    pub receiver_id: AccountId,
+
+    // How many tokens are locked in this promise chain
+    pub amount: Balance,
+
+    // How many tokens were returned to the sender when the chain completed
+    pub refunded: Balance,
+
+    // Block when send() was called
+    pub created_block: BlockHeight,
+
+    // Has the promise chain completed
+    pub status: TransferStatus,
+
+    // What the receiving smart contract told about the transfer, if it was a smart contract
+    pub result: Option<ReceiverResult>,
+
+    // The transfer whose receiver started this transfer as a follow-up
+    pub parent_id: Option<TransferId>,
+
+    // How many tokens the receiver forwarded in a follow-up transfer
+    pub forwarded: Balance,
+}
+
+/// JSON presentation of a transfer for view methods
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct TransferView {
+    pub transfer_id: TransferId,
+    pub sender_id: AccountId,
+    pub receiver_id: AccountId,
+    pub amount: U128,
+    pub refunded: U128,
+    pub created_block: BlockHeight,
+    pub status: TransferStatus,
+    pub result: Option<ReceiverResult>,
+    pub parent_id: Option<TransferId>,
+    pub forwarded: U128,
+}
+
+impl TransferView {
+
+    pub fn new(transfer_id: TransferId, transfer: Transfer) -> Self {
+        Self {
+            transfer_id,
+            sender_id: transfer.sender_id,
+            receiver_id: transfer.receiver_id,
+            amount: transfer.amount.into(),
+            refunded: transfer.refunded.into(),
+            created_block: transfer.created_block,
+            status: transfer.status,
+            result: transfer.result,
+            parent_id: transfer.parent_id,
+            forwarded: transfer.forwarded.into(),
+        }
+    }
+}
+
+/// A token holder as returned by get_holders()
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct HolderView {
+    pub account_id: AccountId,
+    pub balance: U128,
+}
+
+/// Result of comparing the sum of all balances to the total supply
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SupplyCheck {
+    pub total_supply: U128,
+    pub balance_sum: U128,
+    pub holder_count: u64,
+    pub matches: bool,
+}
 
 /**
  * A balance ledger that keeps track of rollbackable promise transactions.
  *
- * TODO: Currently we lock balance by account, but this is not very flexible.
- * What we really want to is lock balancy by a promise chain. However, this
- * would need to be able to identify the originating transaction in NEAR
- * and currently I am not sure if this information is exposed
- * on the smart contract level.
+ * Each send() gets its own transfer id and lock record, so parallel
+ * promise chains to the same receiver are finalised and rolled back
+ * independently of each other.
  *
  * https://stackoverflow.com/questions/64170363/tracking-promise-chains-in-near-smart-contract-protocol
  *
@@ -31,18 +194,63 @@ const SINGLE_CALL_GAS: u64 = 200000000000000;
 #[derive(BorshDeserialize, BorshSerialize)]
 pub struct Ledger {
 
-    // Total balances, including locked, for each user
-    pub balances: LookupMap<AccountId, Balance>,
+    // Total balances, including locked, for each user.
+    // Only accounts with a positive balance have an entry, so the keys are the token holders.
+    pub balances: UnorderedMap<AccountId, Balance>,
 
-    /// Account has a pending promise chain in progress
-    /// and balance locked is this chain cannot be withdawn.
+    /// Sum of all pending transfer locks for each receiving account.
+    /// Balance locked in a promise chain cannot be withdawn.
     /// If a promise chain is succesful free the locked balance.
     /// If a promise chain fails, then the send() gets undoed
     pub locked_balances: LookupMap<AccountId, Balance>,
 
+    /// Lock records of all promise chains
+    pub transfers: LookupMap<TransferId, Transfer>,
+
+    /// Ids of pending transfers for each sender and receiver
+    pub pending_transfers: LookupMap<AccountId, UnorderedSet<TransferId>>,
+
+    /// Transfer id given to the next send()
+    pub next_transfer_id: TransferId,
+
+    /// After this many blocks a pending transfer can be rolled back
+    /// by anyone, in the case the promise chain never completes
+    pub lock_expiry_blocks: BlockHeight,
+
     /// Total supply of the token
     pub total_supply: Balance,
 
+    /// Minting cannot take total supply above this
+    pub max_supply: Option<Balance>,
+
+    /// NEP-21 allowances by (owner, escrow) pairs
+    pub allowances: LookupMap<(AccountId, AccountId), Balance>,
+
+    /// Storage staked by each registered account.
+    /// Only registered accounts can hold balances.
+    pub storage_deposits: LookupMap<AccountId, Balance>,
+
+    /// How many bytes the ledger records of one account take
+    pub account_storage_usage: StorageUsage,
+
+    /// Bytes each account has been charged for on top of account_storage_usage:
+    /// transfer records, allowances, delegation and the balance history its transfers write.
+    pub storage_usages: LookupMap<AccountId, StorageUsage>,
+
+    /// Accounts whose account_storage_usage the contract pays.
+    /// They still pay for the records they create.
+    pub sponsored_accounts: LookupSet<AccountId>,
+
+    /// Balance history of each account that has ever held tokens.
+    /// The history grows with every balance change and is kept when the account unregisters.
+    pub balance_checkpoints: AccountCheckpoints,
+
+    /// Total supply history
+    pub supply_checkpoints: Vector<Checkpoint>,
+
+    /// Who votes with the balance of each account
+    pub delegation: Delegation,
+
     /// Helper counter for testing to diagnose
     /// how many rollbacks have occured
     pub rollbacks: u64,
@@ -51,26 +259,399 @@ pub struct Ledger {
 
 impl Ledger {
 
+    /// Empty ledger with no registered accounts
+    pub fn new(max_supply: Option<Balance>, lock_expiry_blocks: BlockHeight) -> Self {
+        Ledger {
+            balances: UnorderedMap::new(b"bal".to_vec()),
+            locked_balances: LookupMap::new(b"lck".to_vec()),
+            transfers: LookupMap::new(b"trf".to_vec()),
+            pending_transfers: LookupMap::new(b"pnd".to_vec()),
+            next_transfer_id: 0,
+            lock_expiry_blocks,
+            total_supply: 0,
+            max_supply,
+            allowances: LookupMap::new(b"alw".to_vec()),
+            storage_deposits: LookupMap::new(b"std".to_vec()),
+            account_storage_usage: 0,
+            storage_usages: LookupMap::new(b"stu".to_vec()),
+            sponsored_accounts: LookupSet::new(b"spn".to_vec()),
+            balance_checkpoints: AccountCheckpoints::new(b"bck", b"bcv"),
+            supply_checkpoints: Vector::new(b"sck".to_vec()),
+            delegation: Delegation {
+                delegates: LookupMap::new(b"dlg".to_vec()),
+                votes: AccountCheckpoints::new(b"vck", b"vcv"),
+            },
+            rollbacks: 0,
+        }
+    }
+
     /// Helper method to get the account details for `owner_id`.
-    fn get_balance(&self, owner_id: &AccountId) -> u128 {
-        match self.balances.get(owner_id) {
-            Some(x) => return x,
+    pub fn get_balance(&self, owner_id: &AccountId) -> u128 {
+        self.balances.get(owner_id).unwrap_or_default()
+    }
+
+    /// How many accounts hold tokens
+    pub fn get_holder_count(&self) -> u64 {
+        self.balances.len()
+    }
+
+    /// Accounts holding tokens and their balances, in no particular order
+    pub fn get_holders(&self, from_index: u64, limit: u64) -> Vec<(AccountId, Balance)> {
+        let keys = self.balances.keys_as_vector();
+        let values = self.balances.values_as_vector();
+        let end = std::cmp::min(from_index.saturating_add(limit), keys.len());
+        (from_index..end)
+            .map(|index| (keys.get(index).unwrap(), values.get(index).unwrap()))
+            .collect()
+    }
+
+    /// Sum of all balances. This must always equal the total supply.
+    pub fn get_balance_sum(&self) -> Balance {
+        self.balances.values_as_vector().iter().sum()
+    }
+
+    /// Helper method to set the account details for `owner_id` to the state.
+    /// `payer_id`, the account that caused the change, pays for the growing balance and vote history.
+    pub(crate) fn set_balance(&mut self, owner_id: &AccountId, balance: Balance, payer_id: &AccountId) -> Result<(), TokenError> {
+        if !self.is_registered(owner_id) {
+            return Err(TokenError::NotRegistered { account_id: owner_id.clone() });
+        }
+        let old_balance = self.get_balance(owner_id);
+        if balance == 0 {
+            self.balances.remove(owner_id);
+        } else {
+            self.balances.insert(owner_id, &balance);
+        }
+        let initial_storage_usage = env::storage_usage();
+        self.balance_checkpoints.write(owner_id, env::block_index(), balance);
+        self.delegation.move_votes(owner_id, old_balance, balance);
+        self.charge_storage(payer_id, initial_storage_usage);
+        Ok(())
+    }
+
+    /// Balance of `owner_id` at the end of block `block_index`
+    pub fn get_balance_at(&self, owner_id: &AccountId, block_index: BlockHeight) -> Balance {
+        self.balance_checkpoints.value_at(owner_id, block_index)
+    }
+
+    /// Let `delegate_id` vote with the balance of `owner_id`, including tokens locked in transfers to it.
+    /// Both accounts must be registered, the owner pays for the vote history it creates.
+    pub fn delegate(&mut self, owner_id: &AccountId, delegate_id: &AccountId) -> Result<(), TokenError> {
+        assert_valid_account_id(delegate_id)?;
+        for account_id in &[owner_id, delegate_id] {
+            if !self.is_registered(account_id) {
+                return Err(TokenError::NotRegistered { account_id: account_id.to_string() });
+            }
+        }
+        let balance = self.get_balance(owner_id);
+        let initial_storage_usage = env::storage_usage();
+        self.delegation.delegate(owner_id, delegate_id, balance);
+        self.charge_storage(owner_id, initial_storage_usage);
+        self.check_storage_deposit(owner_id)
+    }
+
+    /// Helper method to update the total supply and its history
+    pub(crate) fn set_total_supply(&mut self, total_supply: Balance) {
+        self.total_supply = total_supply;
+        write_checkpoint(&mut self.supply_checkpoints, env::block_index(), total_supply);
+    }
+
+    /// Total supply at the end of block `block_index`
+    pub fn get_total_supply_at(&self, block_index: BlockHeight) -> Balance {
+        value_at(&self.supply_checkpoints, block_index)
+    }
+
+    /// Measure how much storage the records every account has take,
+    /// by writing and removing the records of the longest possible account id.
+    /// Records that grow later are charged with charge_storage().
+    pub fn measure_account_storage_usage(&mut self) {
+        let initial_storage_usage = env::storage_usage();
+        let tmp_account_id = "a".repeat(MAX_ACCOUNT_ID_LENGTH);
+        self.storage_deposits.insert(&tmp_account_id, &0);
+        self.storage_usages.insert(&tmp_account_id, &0);
+        self.balances.insert(&tmp_account_id, &1);
+        self.locked_balances.insert(&tmp_account_id, &1);
+        self.account_storage_usage = env::storage_usage() - initial_storage_usage;
+        self.balances.remove(&tmp_account_id);
+        self.locked_balances.remove(&tmp_account_id);
+        self.storage_usages.remove(&tmp_account_id);
+        self.storage_deposits.remove(&tmp_account_id);
+    }
+
+    /// How much an account needs to stake to be registered
+    pub fn get_storage_cost(&self) -> Balance {
+        Balance::from(self.account_storage_usage) * STORAGE_PRICE_PER_BYTE
+    }
+
+    /**
+     * Charge the storage written since `initial_storage_usage` to `account_id`.
+     *
+     * Freed storage is credited back. Nothing is charged to unregistered accounts.
+     * Callbacks must always be able to record the usage, so the stake is checked
+     * separately with check_storage_available() before writing.
+     */
+    fn charge_storage(&mut self, account_id: &AccountId, initial_storage_usage: StorageUsage) {
+        let usage = match self.storage_usages.get(account_id) {
+            Some(x) => x,
+            None => return,
+        };
+        let storage_usage = env::storage_usage();
+        let usage = if storage_usage >= initial_storage_usage {
+            usage + (storage_usage - initial_storage_usage)
+        } else {
+            usage.saturating_sub(initial_storage_usage - storage_usage)
+        };
+        self.storage_usages.insert(account_id, &usage);
+    }
+
+    /// How much `account_id` must keep staked for its records
+    pub fn get_required_storage_deposit(&self, account_id: &AccountId) -> Balance {
+        let records = match self.storage_usages.get(account_id) {
+            Some(usage) => Balance::from(usage) * STORAGE_PRICE_PER_BYTE,
             None => return 0,
+        };
+        if self.sponsored_accounts.contains(account_id) {
+            return records;
         }
+        self.get_storage_cost() + records
     }
 
-    /// Helper method to set the account details for `owner_id` to the state.
-    fn set_balance(&mut self, owner_id: &AccountId, balance: Balance) {
-        assert!(env::is_valid_account_id(owner_id.as_bytes()), "Owner's account ID is invalid");
-        self.balances.insert(owner_id, &balance);
+    /// Check the storage stake of `account_id` covers its records
+    pub fn check_storage_deposit(&self, account_id: &AccountId) -> Result<(), TokenError> {
+        self.check_storage_available(account_id, 0)
+    }
+
+    /// Check the storage stake of `account_id` covers its records and `usage` more bytes.
+    /// Called before writing, with an upper bound of what the writes charge to the account.
+    pub fn check_storage_available(&self, account_id: &AccountId, usage: StorageUsage) -> Result<(), TokenError> {
+        let deposit = self.get_registered_storage_deposit(account_id)?;
+        let required = self.get_required_storage_deposit(account_id) + Balance::from(usage) * STORAGE_PRICE_PER_BYTE;
+        if deposit < required {
+            return Err(TokenError::StorageDepositTooLow { account_id: account_id.clone(), required, deposit });
+        }
+        Ok(())
+    }
+
+    pub fn is_registered(&self, account_id: &AccountId) -> bool {
+        self.storage_deposits.contains_key(account_id)
+    }
+
+    fn check_registered(&self, account_id: &AccountId) -> Result<(), TokenError> {
+        if !self.is_registered(account_id) {
+            return Err(TokenError::NotRegistered { account_id: account_id.clone() });
+        }
+        Ok(())
+    }
+
+    /// Returns how much `account_id` has staked for storage, or None if the account is not registered
+    pub fn get_storage_deposit(&self, account_id: &AccountId) -> Option<Balance> {
+        self.storage_deposits.get(account_id)
+    }
+
+    /// Register `account_id` so it can hold balances, staking `deposit` for its storage.
+    pub fn register(&mut self, account_id: &AccountId, deposit: Balance) -> Result<(), TokenError> {
+        assert_valid_account_id(account_id)?;
+        if self.is_registered(account_id) {
+            return Err(TokenError::AlreadyRegistered { account_id: account_id.clone() });
+        }
+        self.storage_deposits.insert(account_id, &deposit);
+        self.storage_usages.insert(account_id, &0);
+        Ok(())
+    }
+
+    /// Register `account_id` so it can hold balances, with the contract paying for the account record.
+    /// The records the account creates later are charged to `deposit` like for any other account.
+    pub fn register_sponsored(&mut self, account_id: &AccountId, deposit: Balance) -> Result<(), TokenError> {
+        self.register(account_id, deposit)?;
+        self.sponsored_accounts.insert(account_id);
+        Ok(())
+    }
+
+    /// Add `amount` to the storage stake of a registered account
+    pub fn add_storage_deposit(&mut self, account_id: &AccountId, amount: Balance) -> Result<(), TokenError> {
+        let deposit = self.get_registered_storage_deposit(account_id)?;
+        self.storage_deposits.insert(account_id, &(deposit + amount));
+        Ok(())
+    }
+
+    /// Take `amount` from the part of the storage stake that is not needed for the account records
+    pub fn withdraw_storage_deposit(&mut self, account_id: &AccountId, amount: Balance) -> Result<(), TokenError> {
+        let deposit = self.get_registered_storage_deposit(account_id)?;
+        let available = deposit.saturating_sub(self.get_required_storage_deposit(account_id));
+        if amount > available {
+            return Err(TokenError::StorageWithdrawTooMuch { amount, available });
+        }
+        self.storage_deposits.insert(account_id, &(deposit - amount));
+        Ok(())
+    }
+
+    /**
+     * Remove the account records and return the staked storage deposit.
+     *
+     * An account with pending transfers cannot be unregistered, as the transfers
+     * may still need to refund tokens to it. With `force` the remaining unlocked
+     * balance is burned, otherwise the balance must be zero.
+     *
+     * The balance history is kept, so get_balance_at() still answers for past blocks,
+     * and the delegation is removed. Records that stay, like completed transfers,
+     * allowances given and the history the account has paid for, keep their share of the deposit.
+     */
+    pub fn unregister(&mut self, account_id: &AccountId, force: bool) -> Result<Balance, TokenError> {
+        let deposit = self.get_registered_storage_deposit(account_id)?;
+        if self.pending_transfers.contains_key(account_id) {
+            return Err(TokenError::UnregisterPendingTransfers);
+        }
+        let balance = self.get_balance(account_id);
+        if balance > 0 {
+            if !force {
+                return Err(TokenError::UnregisterPositiveBalance);
+            }
+            // The history ends at zero
+            self.set_balance(account_id, 0, account_id)?;
+            let initial_storage_usage = env::storage_usage();
+            self.set_total_supply(self.total_supply - balance);
+            self.charge_storage(account_id, initial_storage_usage);
+            TokenEvent::Burn {
+                owner_id: account_id.clone(),
+                amount: balance.into(),
+            }.emit();
+        }
+        let initial_storage_usage = env::storage_usage();
+        self.delegation.delegates.remove(account_id);
+        self.charge_storage(account_id, initial_storage_usage);
+
+        let kept = Balance::from(self.storage_usages.get(account_id).unwrap_or(0)) * STORAGE_PRICE_PER_BYTE;
+        self.storage_deposits.remove(account_id);
+        self.storage_usages.remove(account_id);
+        self.sponsored_accounts.remove(account_id);
+        Ok(deposit.saturating_sub(kept))
+    }
+
+    fn get_registered_storage_deposit(&self, account_id: &AccountId) -> Result<Balance, TokenError> {
+        match self.storage_deposits.get(account_id) {
+            Some(x) => Ok(x),
+            None => Err(TokenError::NotRegistered { account_id: account_id.clone() }),
+        }
     }
 
     /// Helper method to get the account details for `owner_id`.
-    fn get_locked_balance(&self, owner_id: &AccountId) -> Balance {
-        match self.locked_balances.get(owner_id) {
-            Some(x) => return x,
-            None => return 0,
+    pub fn get_locked_balance(&self, owner_id: &AccountId) -> Balance {
+        self.locked_balances.get(owner_id).unwrap_or_default()
+    }
+
+    /// Helper method to update the lock total of `owner_id`.
+    fn set_locked_balance(&mut self, owner_id: &AccountId, locked: Balance) {
+        if locked == 0 {
+            self.locked_balances.remove(owner_id);
+        } else {
+            self.locked_balances.insert(owner_id, &locked);
+        }
+    }
+
+    /// Get the lock record of a promise chain
+    pub fn get_transfer(&self, transfer_id: TransferId) -> Result<Transfer, TokenError> {
+        match self.transfers.get(&transfer_id) {
+            Some(x) => Ok(x),
+            None => Err(TokenError::UnknownTransfer { transfer_id }),
+        }
+    }
+
+    /// Get the lock record of a promise chain that has not completed yet
+    fn get_pending_transfer(&self, transfer_id: TransferId) -> Result<Transfer, TokenError> {
+        let transfer = self.get_transfer(transfer_id)?;
+        if transfer.status != TransferStatus::Pending {
+            return Err(TokenError::TransferNotPending { transfer_id });
+        }
+        Ok(transfer)
+    }
+
+    /// Has the promise chain of the transfer been stuck long enough to be reclaimed
+    pub fn is_expired(&self, transfer: &Transfer) -> bool {
+        transfer.status == TransferStatus::Pending
+            && env::block_index() >= transfer.created_block + self.lock_expiry_blocks
+    }
+
+    /// Pending transfer ids where `account_id` is the sender or the receiver, in no particular order
+    pub fn get_pending_transfer_ids(&self, account_id: &AccountId, from_index: u64, limit: u64) -> Vec<TransferId> {
+        match self.pending_transfers.get(account_id) {
+            Some(ids) => ids.iter().skip(from_index as usize).take(limit as usize).collect(),
+            None => vec![],
+        }
+    }
+
+    /// Is `transfer_id` one of the pending transfers of `account_id`
+    pub fn is_pending_for(&self, account_id: &AccountId, transfer_id: TransferId) -> bool {
+        match self.pending_transfers.get(account_id) {
+            Some(ids) => ids.contains(&transfer_id),
+            None => false,
+        }
+    }
+
+    fn add_pending_transfer(&mut self, account_id: &AccountId, transfer_id: TransferId) {
+        let mut ids = match self.pending_transfers.get(account_id) {
+            Some(x) => x,
+            None => {
+                let mut prefix = PENDING_SET_PREFIX.to_vec();
+                prefix.extend_from_slice(account_id.as_bytes());
+                UnorderedSet::new(prefix)
+            },
+        };
+        ids.insert(&transfer_id);
+        self.pending_transfers.insert(account_id, &ids);
+    }
+
+    fn remove_pending_transfer(&mut self, account_id: &AccountId, transfer_id: TransferId) {
+        if let Some(mut ids) = self.pending_transfers.get(account_id) {
+            ids.remove(&transfer_id);
+            if ids.is_empty() {
+                self.pending_transfers.remove(account_id);
+            } else {
+                self.pending_transfers.insert(account_id, &ids);
+            }
+        }
+    }
+
+    /// Mark the promise chain completed. The sender gets the storage of the pending entries back.
+    fn complete_transfer(&mut self, transfer_id: TransferId, mut transfer: Transfer, status: TransferStatus, refunded: Balance) {
+        let initial_storage_usage = env::storage_usage();
+        self.remove_pending_transfer(&transfer.sender_id, transfer_id);
+        self.remove_pending_transfer(&transfer.receiver_id, transfer_id);
+        transfer.status = status;
+        transfer.refunded = refunded;
+        self.transfers.insert(&transfer_id, &transfer);
+        self.charge_storage(&transfer.sender_id, initial_storage_usage);
+    }
+
+    /// Check `amount` can be moved from the unlocked balance of `owner_id` to `new_owner_id`, without writing anything
+    fn check_transfer(&self, owner_id: &AccountId, new_owner_id: &AccountId, amount: Balance) -> Result<(), TokenError> {
+        check_recipient(owner_id, new_owner_id, amount)?;
+        self.check_registered(new_owner_id)?;
+        self.check_debit(owner_id, amount)
+    }
+
+    /// Check `amount` can be taken from the unlocked balance of `owner_id`
+    fn check_debit(&self, owner_id: &AccountId, amount: Balance) -> Result<(), TokenError> {
+        // Retrieving the account from the state.
+        let source_balance = self.get_balance(owner_id);
+        let source_lock = self.get_locked_balance(owner_id);
+
+        // Checking unlocked balance
+        if source_balance < amount {
+            return Err(TokenError::NotEnoughBalance { amount, balance: source_balance });
+        }
+
+        // Checking unlocked balance
+        if source_balance < amount + source_lock {
+            return Err(TokenError::BalanceLocked { amount, balance: source_balance, locked: source_lock });
         }
+        self.check_registered(owner_id)
+    }
+
+    /// Take `amount` from the unlocked balance of `owner_id`
+    fn debit(&mut self, owner_id: &AccountId, amount: Balance) -> Result<(), TokenError> {
+        self.check_debit(owner_id, amount)?;
+        let source_balance = self.get_balance(owner_id);
+        self.set_balance(owner_id, source_balance - amount, owner_id)
     }
 
     /**
@@ -79,147 +660,530 @@ impl Ledger {
      * message is an optional byte data that is passed to the receiving smart contract.
      * notify is a flag to tell if we are going to call a smart contract, because this cannot be currently resolved run-time
      * within NEAR smart contract.
+     *
+     * Returns the id of the lock record created for this promise chain.
+     * The promise chain is not returned, so the caller gets the transfer id
+     * back immediately and can follow the chain with the transfer view methods.
+     *
+     * The chain gets the gas this call has left, so at least NOTIFY_GAS must be left.
      */
-    pub fn send(&mut self, owner_id: AccountId, new_owner_id: AccountId, amount: Balance, message: Vec<u8>) {
+    pub fn send(&mut self, owner_id: AccountId, new_owner_id: AccountId, amount: Balance, message: Vec<u8>) -> Result<TransferId, TokenError> {
+        check_notify_gas()?;
+        let transfer_id = self.lock(owner_id, new_owner_id, amount)?;
+        self.notify_receiver(transfer_id, message, RECEIVER_CHECK_GAS, remaining_handle_gas())?;
+        Ok(transfer_id)
+    }
 
-        assert!(
-            env::is_valid_account_id(new_owner_id.as_bytes()),
-            "New owner's account ID is invalid"
-        );
-        let amount = amount.into();
-        if amount == 0 {
-            env::panic(b"Can't transfer 0 tokens");
+    /**
+     * Send tokens to several new owners.
+     *
+     * The total is taken from the sender at once. Each recipient gets its own transfer
+     * and its own promise chain, also when its message is empty, so one failing receiver
+     * only rolls back its own transfer.
+     *
+     * Returns the transfer ids in the order of `transfers`.
+     * Nothing is written if any of the transfers is invalid.
+     */
+    pub fn send_batch(&mut self, owner_id: AccountId, transfers: Vec<(AccountId, Balance, Vec<u8>)>) -> Result<Vec<TransferId>, TokenError> {
+        let (recipients, messages): (Vec<_>, Vec<_>) = transfers
+            .into_iter()
+            .map(|(new_owner_id, amount, message)| ((new_owner_id, amount), message))
+            .unzip();
+        let transfer_ids = self.lock_batch(&owner_id, recipients)?;
+        for (transfer_id, message) in transfer_ids.iter().zip(messages) {
+            self.notify_receiver(*transfer_id, message, BATCH_CHECK_GAS, BATCH_HANDLE_GAS)?;
         }
-        assert_ne!(
-            owner_id, new_owner_id,
-            "The new owner should be different from the current owner"
-        );
-        // Retrieving the account from the state.
-        let source_balance = self.get_balance(&owner_id);
-        let source_lock = self.get_locked_balance(&owner_id);
+        Ok(transfer_ids)
+    }
 
-        // Checking and updating unlocked balance
-        if source_balance < amount {
-            env::panic(format!("Not enough balance, need {}, has {}", amount, source_balance).as_bytes());
+    /**
+     * Pay tokens to several new owners without notifying them.
+     *
+     * Like send_batch(), but each transfer is finalised at once, like transfer().
+     * Contracts among the recipients never learn about the tokens they got.
+     *
+     * Returns the transfer ids in the order of `transfers`.
+     * Nothing is written if any of the transfers is invalid.
+     */
+    pub fn pay_batch(&mut self, owner_id: AccountId, transfers: Vec<(AccountId, Balance)>) -> Result<Vec<TransferId>, TokenError> {
+        let transfer_ids = self.lock_batch(&owner_id, transfers)?;
+        for transfer_id in transfer_ids.iter() {
+            self.finalise(*transfer_id)?;
+        }
+        Ok(transfer_ids)
+    }
+
+    /// Take the total of a batch from `owner_id` and lock a transfer for each recipient.
+    /// Nothing is written if any of the transfers is invalid.
+    fn lock_batch(&mut self, owner_id: &AccountId, transfers: Vec<(AccountId, Balance)>) -> Result<Vec<TransferId>, TokenError> {
+        if transfers.is_empty() {
+            return Err(TokenError::EmptyBatch);
         }
 
-        // Checking and updating unlocked balance
-        if source_balance < amount + source_lock {
-            env::panic(format!("Cannot send {} tokens, as account has {} and in tx lock {}", amount, source_balance, source_lock).as_bytes());
+        let mut total: Balance = 0;
+        for (new_owner_id, amount) in transfers.iter() {
+            check_recipient(owner_id, new_owner_id, *amount)?;
+            self.check_registered(new_owner_id)?;
+            total = total.saturating_add(*amount);
         }
-        self.set_balance(&owner_id, source_balance - amount);
+        self.check_debit(owner_id, total)?;
+        let count = transfers.len() as StorageUsage;
+        self.check_storage_available(owner_id, HISTORY_STORAGE_USAGE + (HISTORY_STORAGE_USAGE + TRANSFER_STORAGE_USAGE) * count)?;
+        self.debit(owner_id, total)?;
+
+        let mut transfer_ids = Vec::with_capacity(transfers.len());
+        for (new_owner_id, amount) in transfers {
+            transfer_ids.push(self.create_transfer(owner_id.clone(), new_owner_id, amount, None)?);
+        }
+        Ok(transfer_ids)
+    }
+
+    /**
+     * Move tokens to a new owner and lock them until the caller
+     * finalises or rolls back the transfer.
+     *
+     * Unlike send(), this does not start a promise chain.
+     * The caller is responsible for building its own chain.
+     * Nothing is written if the transfer is invalid.
+     */
+    pub fn lock(&mut self, owner_id: AccountId, new_owner_id: AccountId, amount: Balance) -> Result<TransferId, TokenError> {
+        self.check_transfer(&owner_id, &new_owner_id, amount)?;
+        self.check_storage_available(&owner_id, LOCK_STORAGE_USAGE)?;
+        self.debit(&owner_id, amount)?;
+        self.create_transfer(owner_id, new_owner_id, amount, None)
+    }
+
+    /// Move tokens to a new owner immediately, without a lock or a promise chain.
+    /// Nothing is written if the transfer is invalid.
+    pub fn transfer(&mut self, owner_id: AccountId, new_owner_id: AccountId, amount: Balance) -> Result<(), TokenError> {
+        self.check_transfer(&owner_id, &new_owner_id, amount)?;
+        self.check_storage_available(&owner_id, 2 * HISTORY_STORAGE_USAGE)?;
+        self.move_balance(owner_id, new_owner_id, amount)
+    }
+
+    /// Move the checked `amount` from `owner_id` to `new_owner_id`
+    fn move_balance(&mut self, owner_id: AccountId, new_owner_id: AccountId, amount: Balance) -> Result<(), TokenError> {
+        self.debit(&owner_id, amount)?;
+        let target_balance = self.get_balance(&new_owner_id);
+        self.set_balance(&new_owner_id, target_balance + amount, &owner_id)?;
+
+        TokenEvent::Transfer {
+            sender_id: owner_id,
+            receiver_id: new_owner_id,
+            amount: amount.into(),
+        }.emit();
+        Ok(())
+    }
+
+    /**
+     * Forward tokens of a pending transfer onwards as a follow-up transfer.
+     *
+     * The receiver of the transfer `transfer_id` becomes the sender of the new transfer.
+     * The original transfer stays pending until the follow-up completes:
+     * if the follow-up is rolled back, the original transfer is rolled back as well.
+     */
+    pub fn forward(&mut self, transfer_id: TransferId, new_owner_id: AccountId, amount: Balance, message: Vec<u8>) -> Result<TransferId, TokenError> {
+        let mut transfer = self.get_pending_transfer(transfer_id)?;
+        let owner_id = transfer.receiver_id.clone();
+
+        check_recipient(&owner_id, &new_owner_id, amount)?;
+        self.check_registered(&new_owner_id)?;
+        if transfer.forwarded != 0 {
+            return Err(TokenError::AlreadyForwarded { transfer_id });
+        }
+        if amount > transfer.amount {
+            return Err(TokenError::ForwardTooMuch { transfer_id, amount, transfer_amount: transfer.amount });
+        }
+        check_notify_gas()?;
+        self.check_storage_available(&owner_id, LOCK_STORAGE_USAGE)?;
+
+        // Move the forwarded tokens out of the lock of the original transfer
+        let source_balance = self.get_balance(&owner_id);
+        let source_lock = self.get_locked_balance(&owner_id);
+        self.set_balance(&owner_id, source_balance - amount, &owner_id)?;
+        self.set_locked_balance(&owner_id, source_lock - amount);
+
+        transfer.forwarded = amount;
+        self.transfers.insert(&transfer_id, &transfer);
+
+        let follow_up_id = self.create_transfer(owner_id, new_owner_id, amount, Some(transfer_id))?;
+        self.notify_receiver(follow_up_id, message, RECEIVER_CHECK_GAS, remaining_handle_gas())?;
+        Ok(follow_up_id)
+    }
+
+    /// Credit the already withdrawn amount to the new owner and create the lock record
+    fn create_transfer(&mut self, owner_id: AccountId, new_owner_id: AccountId, amount: Balance, parent_id: Option<TransferId>) -> Result<TransferId, TokenError> {
 
         // Deposit amount to the new owner and save the new account to the state.
+        // The sender pays for the history of the new owner.
         let target_balance = self.get_balance(&new_owner_id);
-        let new_target_balance = target_balance + amount;
-        self.set_balance(&new_owner_id, new_target_balance);
+        self.set_balance(&new_owner_id, target_balance + amount, &owner_id)?;
 
         // This much of user balance is lockedup in promise chains
-        self.set_balance(&new_owner_id, new_target_balance);
-
         let target_lock = self.get_locked_balance(&new_owner_id);
-        self.locked_balances.insert(&new_owner_id, &(target_lock +  amount));
+        self.set_locked_balance(&new_owner_id, target_lock + amount);
+
+        let transfer_id = self.next_transfer_id;
+        self.next_transfer_id += 1;
+
+        let transfer = Transfer {
+            sender_id: owner_id.clone(),
+            receiver_id: new_owner_id.clone(),
+            amount,
+            refunded: 0,
+            created_block: env::block_index(),
+            status: TransferStatus::Pending,
+            result: None,
+            parent_id,
+            forwarded: 0,
+        };
+
+        // The sender pays for the transfer record
+        let initial_storage_usage = env::storage_usage();
+        self.transfers.insert(&transfer_id, &transfer);
+        self.add_pending_transfer(&owner_id, transfer_id);
+        self.add_pending_transfer(&new_owner_id, transfer_id);
+        self.charge_storage(&owner_id, initial_storage_usage);
+
+        TokenEvent::TransferStarted {
+            transfer_id,
+            sender_id: owner_id,
+            receiver_id: new_owner_id,
+            amount: amount.into(),
+            parent_id,
+        }.emit();
+
+        Ok(transfer_id)
+    }
+
+    /// Start the promise chain that checks if the receiver is a smart contract and notifies it
+    fn notify_receiver(&self, transfer_id: TransferId, message: Vec<u8>, check_gas: u64, handle_gas: u64) -> Result<(), TokenError> {
+        let transfer = self.get_transfer(transfer_id)?;
+        let amount_total = self.get_balance(&transfer.receiver_id);
 
         let promise0 = env::promise_create(
-            new_owner_id.clone(),
-            b"is_receiver",
+            transfer.receiver_id,
+            IS_RECEIVER_METHOD.as_bytes(),
             &[],
             0,
-            SINGLE_CALL_GAS/3,
+            check_gas,
         );
 
-        let promise1 = env::promise_then(
+        env::promise_then(
             promise0,
             env::current_account_id(),
             b"handle_receiver",
             json!({
-                "old_owner_id": owner_id,
-                "new_owner_id": new_owner_id,
-                "amount_received": amount.to_string(),
-                "amount_total": new_target_balance.to_string(),
+                "transfer_id": transfer_id,
+                "amount_total": amount_total.to_string(),
                 "message": message,
             }).to_string().as_bytes(),
             0,
-            SINGLE_CALL_GAS/3,
+            handle_gas,
         );
+        Ok(())
+    }
 
-        env::promise_return(promise1);
+    /// A follow-up transfer completed, complete the transfer it was forwarded from
+    fn complete_parent(&mut self, parent_id: Option<TransferId>, status: TransferStatus) -> Result<(), TokenError> {
+        let parent_id = match parent_id {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+
+        if status == TransferStatus::RolledBack {
+            // The forwarded tokens are back with the receiver, put them under the original lock
+            let mut parent = self.get_pending_transfer(parent_id)?;
+            let lock = self.get_locked_balance(&parent.receiver_id);
+            self.set_locked_balance(&parent.receiver_id, lock + parent.forwarded);
+            parent.forwarded = 0;
+            self.transfers.insert(&parent_id, &parent);
+            self.rollback(parent_id)
+        } else {
+            self.finalise(parent_id)
+        }
     }
 
     /// All promise chains have been successful, release balance from the lock
     /// and consider the promise chain final.
-    pub fn finalise(&mut self, new_owner_id: AccountId, amount: Balance) {
-        let target_lock = self.get_locked_balance(&new_owner_id);
+    pub fn finalise(&mut self, transfer_id: TransferId) -> Result<(), TokenError> {
+        let transfer = self.get_pending_transfer(transfer_id)?;
+        let parent_id = transfer.parent_id;
+        let target_lock = self.get_locked_balance(&transfer.receiver_id);
 
-        assert!(
-            target_lock >= amount,
-            "Locked balance cannot go to negative"
-        );
+        // Forwarded tokens were already moved out of the lock
+        let amount = transfer.amount - transfer.forwarded;
+
+        if target_lock < amount {
+            return Err(TokenError::LockUnderflow { account_id: transfer.receiver_id });
+        }
 
-        let new_amount = target_lock -  amount;
+        self.set_locked_balance(&transfer.receiver_id, target_lock - amount);
 
-        self.locked_balances.insert(&new_owner_id, &new_amount);
+        TokenEvent::TransferFinalised {
+            transfer_id,
+            sender_id: transfer.sender_id.clone(),
+            receiver_id: transfer.receiver_id.clone(),
+            amount: transfer.amount.into(),
+            refunded: 0.into(),
+        }.emit();
 
+        self.complete_transfer(transfer_id, transfer, TransferStatus::Finalised, 0);
+        self.complete_parent(parent_id, TransferStatus::Finalised)
+    }
+
+    /// The receiving smart contract used only part of the tokens.
+    /// Release the used amount from the lock and return the rest to the sender.
+    /// The transfer is considered final, the refund is not a rollback.
+    pub fn finalise_partial(&mut self, transfer_id: TransferId, amount_used: Balance) -> Result<(), TokenError> {
+        let transfer = self.get_pending_transfer(transfer_id)?;
+        let parent_id = transfer.parent_id;
+
+        if amount_used > transfer.amount {
+            return Err(TokenError::UsedTooMuch { transfer_id, amount_used, transfer_amount: transfer.amount });
+        }
+
+        let refund = transfer.amount - amount_used;
+        let target_lock = self.get_locked_balance(&transfer.receiver_id);
+        let target_balance = self.get_balance(&transfer.receiver_id);
+        let source_balance = self.get_balance(&transfer.sender_id);
+
+        if target_lock < transfer.amount {
+            return Err(TokenError::LockUnderflow { account_id: transfer.receiver_id });
+        }
+
+        self.set_locked_balance(&transfer.receiver_id, target_lock - transfer.amount);
+
+        // Return the unused tokens
+        self.set_balance(&transfer.receiver_id, target_balance - refund, &transfer.sender_id)?;
+        self.set_balance(&transfer.sender_id, source_balance + refund, &transfer.sender_id)?;
+
+        TokenEvent::TransferFinalised {
+            transfer_id,
+            sender_id: transfer.sender_id.clone(),
+            receiver_id: transfer.receiver_id.clone(),
+            amount: transfer.amount.into(),
+            refunded: refund.into(),
+        }.emit();
+
+        self.complete_transfer(transfer_id, transfer, TransferStatus::Finalised, refund);
+        self.complete_parent(parent_id, TransferStatus::Finalised)
+    }
+
+    /// Store what the receiving smart contract told about the transfer. The receiver pays for the storage.
+    pub fn set_transfer_result(&mut self, transfer_id: TransferId, result: ReceiverResult) -> Result<(), TokenError> {
+        let mut transfer = self.get_transfer(transfer_id)?;
+        transfer.result = Some(result);
+        let initial_storage_usage = env::storage_usage();
+        self.transfers.insert(&transfer_id, &transfer);
+        self.charge_storage(&transfer.receiver_id, initial_storage_usage);
+        Ok(())
+    }
+
+    /// The promise chain never completed. Return the locked balance to the sender.
+    pub fn reclaim_expired(&mut self, transfer_id: TransferId) -> Result<(), TokenError> {
+        let transfer = self.get_pending_transfer(transfer_id)?;
+        if !self.is_expired(&transfer) {
+            return Err(TokenError::TransferNotExpired { transfer_id, until_block: transfer.created_block + self.lock_expiry_blocks });
+        }
+        self.rollback(transfer_id)
     }
 
     /// Smart contract call failed. We need to roll back the balance update
-    pub fn rollback(&mut self, old_owner_id: AccountId, new_owner_id: AccountId, amount: Balance) {
+    pub fn rollback(&mut self, transfer_id: TransferId) -> Result<(), TokenError> {
+        let transfer = self.get_pending_transfer(transfer_id)?;
+        let parent_id = transfer.parent_id;
+        let amount = transfer.amount;
+        let old_owner_id = transfer.sender_id.clone();
+        let new_owner_id = transfer.receiver_id.clone();
+
         let target_lock = self.get_locked_balance(&new_owner_id);
         let target_balance = self.get_balance(&new_owner_id);
         let source_balance = self.get_balance(&old_owner_id);
 
-        env::log(format!("Rolling back back send of {}, from {} to {}, currently locked {}", amount, old_owner_id, new_owner_id, target_lock).as_bytes());
-        env::log(format!("New owner balance {}, old owner balance {}", target_balance, source_balance).as_bytes());
+        if transfer.forwarded > 0 {
+            return Err(TokenError::TransferForwarded { transfer_id });
+        }
 
-        assert!(
-            target_lock >= amount,
-            "Locked balance cannot go to negative"
-        );
+        if target_lock < amount {
+            return Err(TokenError::LockUnderflow { account_id: new_owner_id });
+        }
 
         // Roll back lock
-        let new_amount = target_lock - amount;
-        self.locked_balances.insert(&new_owner_id, &new_amount);
-        self.balances.insert(&new_owner_id, &new_amount);
+        self.set_locked_balance(&new_owner_id, target_lock - amount);
 
         // Rollback new owner
         let new_target_balance = target_balance - amount;
-        self.set_balance(&new_owner_id, new_target_balance);
+        self.set_balance(&new_owner_id, new_target_balance, &old_owner_id)?;
 
         // Rollback old owner
         let new_source_balance = source_balance + amount;
-        self.set_balance(&old_owner_id, new_source_balance);
+        self.set_balance(&old_owner_id, new_source_balance, &old_owner_id)?;
 
-        let target_balance = self.get_balance(&new_owner_id);
-        let source_balance = self.get_balance(&old_owner_id);
+        TokenEvent::TransferRolledBack {
+            transfer_id,
+            sender_id: old_owner_id,
+            receiver_id: new_owner_id,
+            amount: amount.into(),
+        }.emit();
 
+        self.complete_transfer(transfer_id, transfer, TransferStatus::RolledBack, amount);
         self.rollbacks += 1;
+        self.complete_parent(parent_id, TransferStatus::RolledBack)
+    }
+
+    /// How many tokens `escrow_account_id` can move on behalf of `owner_id`
+    pub fn get_allowance(&self, owner_id: &AccountId, escrow_account_id: &AccountId) -> Balance {
+        self.allowances.get(&(owner_id.clone(), escrow_account_id.clone())).unwrap_or_default()
+    }
+
+    /// Helper method to update the allowance of `escrow_account_id`. The owner pays for the storage.
+    fn set_allowance(&mut self, owner_id: &AccountId, escrow_account_id: &AccountId, allowance: Balance) {
+        let key = (owner_id.clone(), escrow_account_id.clone());
+        let initial_storage_usage = env::storage_usage();
+        if allowance == 0 {
+            self.allowances.remove(&key);
+        } else {
+            self.allowances.insert(&key, &allowance);
+        }
+        self.charge_storage(owner_id, initial_storage_usage);
+    }
+
+    /// Increase how many tokens `escrow_account_id` can move on behalf of `owner_id`
+    pub fn inc_allowance(&mut self, owner_id: &AccountId, escrow_account_id: &AccountId, amount: Balance) -> Result<(), TokenError> {
+        assert_valid_account_id(escrow_account_id)?;
+        if owner_id == escrow_account_id {
+            return Err(TokenError::SelfAllowance);
+        }
+        // The owner stakes the storage of the allowance
+        self.get_registered_storage_deposit(owner_id)?;
+        let allowance = self.get_allowance(owner_id, escrow_account_id);
+        self.set_allowance(owner_id, escrow_account_id, allowance.saturating_add(amount));
+        self.check_storage_deposit(owner_id)
+    }
+
+    /// Decrease how many tokens `escrow_account_id` can move on behalf of `owner_id`.
+    /// The allowance does not go below zero.
+    pub fn dec_allowance(&mut self, owner_id: &AccountId, escrow_account_id: &AccountId, amount: Balance) {
+        let allowance = self.get_allowance(owner_id, escrow_account_id);
+        self.set_allowance(owner_id, escrow_account_id, allowance.saturating_sub(amount));
+    }
+
+    /// Move tokens of `owner_id` using the allowance given to `escrow_account_id`.
+    /// Tokens locked in promise chains cannot be moved.
+    pub fn transfer_from(&mut self, escrow_account_id: &AccountId, owner_id: AccountId, new_owner_id: AccountId, amount: Balance) -> Result<(), TokenError> {
+        let allowance = self.get_allowance(&owner_id, escrow_account_id);
+        if escrow_account_id != &owner_id && allowance < amount {
+            return Err(TokenError::NotEnoughAllowance { amount, allowance });
+        }
+        self.check_transfer(&owner_id, &new_owner_id, amount)?;
+        self.check_storage_available(&owner_id, 2 * HISTORY_STORAGE_USAGE)?;
+
+        if escrow_account_id != &owner_id {
+            self.set_allowance(&owner_id, escrow_account_id, allowance - amount);
+        }
+        self.move_balance(owner_id, new_owner_id, amount)
+    }
+
+    /// Create new tokens to `account_id`
+    /// `minter_id` pays for the balance and supply history.
+    pub fn mint(&mut self, account_id: &AccountId, amount: Balance, minter_id: &AccountId) -> Result<(), TokenError> {
+        if amount == 0 {
+            return Err(TokenError::ZeroAmount);
+        }
+
+        let new_total_supply = self.total_supply + amount;
+        if let Some(max_supply) = self.max_supply {
+            if new_total_supply > max_supply {
+                return Err(TokenError::MaxSupplyExceeded { amount, max_supply });
+            }
+        }
+        self.check_registered(account_id)?;
+        self.check_storage_available(minter_id, 2 * HISTORY_STORAGE_USAGE)?;
+
+        let balance = self.get_balance(account_id);
+        self.set_balance(account_id, balance + amount, minter_id)?;
+        let initial_storage_usage = env::storage_usage();
+        self.set_total_supply(new_total_supply);
+        self.charge_storage(minter_id, initial_storage_usage);
+
+        TokenEvent::Mint {
+            owner_id: account_id.clone(),
+            amount: amount.into(),
+        }.emit();
+        Ok(())
+    }
+
+    /// Destroy tokens of `account_id`. Tokens locked in promise chains cannot be burnt.
+    /// `burner_id` pays for the balance and supply history.
+    pub fn burn(&mut self, account_id: &AccountId, amount: Balance, burner_id: &AccountId) -> Result<(), TokenError> {
+        if amount == 0 {
+            return Err(TokenError::ZeroAmount);
+        }
+
+        let balance = self.get_balance(account_id);
+        let lock = self.get_locked_balance(account_id);
+        if balance < amount + lock {
+            return Err(TokenError::BurnLocked { amount, balance, locked: lock });
+        }
+        self.check_storage_available(burner_id, 2 * HISTORY_STORAGE_USAGE)?;
+
+        self.set_balance(account_id, balance - amount, burner_id)?;
+        let initial_storage_usage = env::storage_usage();
+        self.set_total_supply(self.total_supply - amount);
+        self.charge_storage(burner_id, initial_storage_usage);
+
+        TokenEvent::Burn {
+            owner_id: account_id.clone(),
+            amount: amount.into(),
+        }.emit();
+        Ok(())
     }
 }
 
 
-/*
- * Information about the token.
- *
- * We hold the name, symbol and homepage readibly available on chain, but other information must be
- * from the JSON data. This way we do not bloat the chain size and also make upgrading the information
- * somewhat easier.
- *
- * All metadata fields are optional.
- */
-#[derive(BorshDeserialize, BorshSerialize)]
- pub struct Metadata {
+/// Check `amount` can be sent from `owner_id` to `new_owner_id`
+fn check_recipient(owner_id: &AccountId, new_owner_id: &AccountId, amount: Balance) -> Result<(), TokenError> {
+    assert_valid_account_id(new_owner_id)?;
+    if amount == 0 {
+        return Err(TokenError::ZeroAmount);
+    }
+    if owner_id == new_owner_id {
+        return Err(TokenError::SameOwner);
+    }
+    Ok(())
+}
 
-    // Name of the token
-    pub name: String,
+fn assert_valid_account_id(account_id: &AccountId) -> Result<(), TokenError> {
+    if !env::is_valid_account_id(account_id.as_bytes()) {
+        return Err(TokenError::InvalidAccountId { account_id: account_id.clone() });
+    }
+    Ok(())
+}
 
-    // Symbol of the token
-    pub symbol: String,
+/// Check this call has enough gas left to start a promise chain with notify_receiver()
+fn check_notify_gas() -> Result<(), TokenError> {
+    let available = env::prepaid_gas() - env::used_gas();
+    if available < NOTIFY_GAS {
+        return Err(TokenError::NotEnoughGas { required: NOTIFY_GAS, available });
+    }
+    Ok(())
+}
 
-    // URL to the human readable page about the token
-    pub web_link: String,
+/// Check this call has `gas_per_transfer` left for each of the `count` transfers of a batch
+fn check_batch_gas(count: usize, gas_per_transfer: u64) -> Result<(), TokenError> {
+    let required = gas_per_transfer * count as u64;
+    let available = env::prepaid_gas() - env::used_gas();
+    if available < required {
+        return Err(TokenError::NotEnoughGas { required, available });
+    }
+    Ok(())
+}
 
-    // URL to the metadata file with more information about the token, like different icon sets
-    pub metadata_link: String,
+/// Gas left for handle_receiver() when this call starts a promise chain now, at most SINGLE_CALL_GAS
+fn remaining_handle_gas() -> u64 {
+    let available = (env::prepaid_gas() - env::used_gas()).saturating_sub(NOTIFY_RESERVE_GAS + RECEIVER_CHECK_GAS);
+    std::cmp::min(available, SINGLE_CALL_GAS)
 }
 
 
@@ -232,7 +1196,16 @@ pub struct Token {
 
     pub ledger: Ledger,
 
-    pub metadata: Metadata,
+    pub metadata: VersionedMetadata,
+
+    /// Account that can manage the token metadata and minters
+    pub owner_id: AccountId,
+
+    /// Exact deposit that send() and the admin methods require
+    pub confirmation_deposit: Balance,
+
+    /// Accounts that can create and destroy tokens
+    pub minters: Vec<AccountId>,
 }
 
 
@@ -247,52 +1220,104 @@ impl Default for Token {
 impl Token {
 
     /// Initializes the contract with the given total supply owned by the given `owner_id`.
+    /// `owner_id` also becomes the owner of the token that can update the metadata.
+    /// `max_supply` optionally caps how many tokens can ever be minted.
+    /// `lock_expiry_blocks` tells how long a stuck send() keeps the balance locked.
     #[init]
-    pub fn new(owner_id: AccountId, total_supply: Balance) -> Self {
+    pub fn new(owner_id: AccountId, total_supply: Balance, metadata: Option<Metadata>, max_supply: Option<Balance>, lock_expiry_blocks: Option<BlockHeight>) -> Self {
 
         assert!(!env::state_exists(), "Already initialized");
 
-        let total_supply = total_supply.into();
+        if let Some(max_supply) = max_supply {
+            if total_supply > max_supply {
+                TokenError::InitialSupplyExceedsMax.panic();
+            }
+        }
 
         // Initialize the ledger with the initial total supply
-        let ledger = Ledger {
-            balances: LookupMap::new(b"bal".to_vec()),
-            locked_balances: LookupMap::new(b"lck".to_vec()),
-            total_supply,
-            rollbacks: 0,
-        };
+        let ledger = Ledger::new(max_supply, lock_expiry_blocks.unwrap_or(DEFAULT_LOCK_EXPIRY_BLOCKS));
 
-        // Currently the constructor does not support passing of metadata.
-        // Start with empty metadata, owner needs to initialize this
-        // after the token has been created in another transaction
-        let metadata = Metadata {
-            name: String::from(""),
-            symbol: String::from(""),
-            web_link: String::from(""),
-            metadata_link: String::from(""),
-        };
+        // If metadata is not given, start with empty metadata.
+        // The owner can initialize this after the token has been created
+        // in another transaction.
+        let metadata = metadata.unwrap_or_default();
+        metadata.validate().unwrap_or_panic();
 
         let mut token = Self {
             ledger,
-            metadata
+            metadata: metadata.into(),
+            owner_id: owner_id.clone(),
+            minters: vec![],
+            confirmation_deposit: DEFAULT_CONFIRMATION_DEPOSIT,
         };
-        token.ledger.set_balance(&owner_id, total_supply);
-        return token;
+        // The contract pays the storage of the owner account.
+        // The owner pays for its balance history and must stake a deposit before sending tokens.
+        token.ledger.measure_account_storage_usage();
+        token.ledger.register_sponsored(&owner_id, 0).unwrap_or_panic();
+        token.ledger.set_balance(&owner_id, total_supply, &owner_id).unwrap_or_panic();
+        token.ledger.set_total_supply(total_supply);
+        TokenEvent::Mint {
+            owner_id,
+            amount: total_supply.into(),
+        }.emit();
+        token
     }
 
     /// Returns total supply of tokens.
     pub fn get_total_supply(&self) -> Balance {
-        self.ledger.total_supply.into()
+        self.ledger.total_supply
+    }
+
+    /// Returns how many accounts hold tokens
+    pub fn get_holder_count(&self) -> u64 {
+        self.ledger.get_holder_count()
+    }
+
+    /// Returns accounts holding tokens with their balances, including locked tokens.
+    /// The order changes when accounts sell all their tokens.
+    pub fn get_holders(&self, from_index: u64, limit: u64) -> Vec<HolderView> {
+        self.ledger.get_holders(from_index, limit)
+            .into_iter()
+            .map(|(account_id, balance)| HolderView { account_id, balance: balance.into() })
+            .collect()
+    }
+
+    /// Sum all balances and compare them to the total supply.
+    /// This reads every holder, so it is meant for off-chain checks only.
+    pub fn get_supply_check(&self) -> SupplyCheck {
+        let balance_sum = self.ledger.get_balance_sum();
+        SupplyCheck {
+            total_supply: self.ledger.total_supply.into(),
+            balance_sum: balance_sum.into(),
+            holder_count: self.ledger.get_holder_count(),
+            matches: balance_sum == self.ledger.total_supply,
+        }
+    }
+
+    /// Returns the balance of `owner_id` at the end of block `block_index`.
+    /// Use a block that has already passed for snapshots.
+    pub fn get_balance_at(&self, owner_id: AccountId, block_index: BlockHeight) -> Balance {
+        self.ledger.get_balance_at(&owner_id, block_index)
+    }
+
+    /// Returns the total supply at the end of block `block_index`
+    pub fn get_total_supply_at(&self, block_index: BlockHeight) -> Balance {
+        self.ledger.get_total_supply_at(block_index)
+    }
+
+    /// Returns the supply cap, or null if the supply is not capped
+    pub fn get_max_supply(&self) -> Option<Balance> {
+        self.ledger.max_supply
     }
 
     /// Returns balance of the `owner_id` account.
     pub fn get_balance(&self, owner_id: AccountId) -> Balance {
-        self.ledger.get_balance(&owner_id).into()
+        self.ledger.get_balance(&owner_id)
     }
 
     /// Returns balance lockedin pending transactions
     pub fn get_locked_balance(&self, owner_id: AccountId) -> Balance {
-        self.ledger.get_locked_balance(&owner_id).into()
+        self.ledger.get_locked_balance(&owner_id)
     }
 
     //// How many rollbacks we have had
@@ -300,15 +1325,209 @@ impl Token {
         self.ledger.rollbacks
     }
 
+    /// Returns the status of a send() promise chain, or null if the transfer id is unknown
+    pub fn get_transfer(&self, transfer_id: TransferId) -> Option<TransferView> {
+        self.ledger.transfers.get(&transfer_id).map(|transfer| TransferView::new(transfer_id, transfer))
+    }
+
+    /// Returns pending transfers where `account_id` is the sender or the receiver.
+    /// Completed transfers are swapped out of the list, so the order changes as transfers complete.
+    pub fn get_pending_transfers(&self, account_id: AccountId, from_index: u64, limit: u64) -> Vec<TransferView> {
+        self.ledger.get_pending_transfer_ids(&account_id, from_index, limit)
+            .into_iter()
+            .map(|transfer_id| TransferView::new(transfer_id, self.ledger.get_transfer(transfer_id).unwrap_or_panic()))
+            .collect()
+    }
+
     /// Returns balance of the `owner_id` account.
-    pub fn get_name(&self) -> &str {
-        return &self.metadata.name;
+    pub fn get_name(&self) -> String {
+        self.metadata.get().name
+    }
+
+    /// Returns all metadata fields
+    pub fn get_metadata(&self) -> Metadata {
+        self.metadata.get()
+    }
+
+    /// Returns the account that manages the token
+    pub fn get_owner(&self) -> AccountId {
+        self.owner_id.clone()
+    }
+
+    /// Replace the token metadata. Only the owner can do this.
+    #[payable]
+    pub fn update_metadata(&mut self, metadata: Metadata) {
+        self.assert_owner();
+        self.take_confirmation_deposit();
+        metadata.validate().unwrap_or_panic();
+        TokenEvent::MetadataUpdated {
+            metadata: metadata.clone(),
+        }.emit();
+        self.metadata = metadata.into();
+    }
+
+    /// Returns how many yoctoNEAR send(), the NEP-141 transfers, storage withdrawals and the admin methods require
+    pub fn get_confirmation_deposit(&self) -> U128 {
+        self.confirmation_deposit.into()
+    }
+
+    /// Change the deposit that get_confirmation_deposit() lists. Only the owner can do this.
+    #[payable]
+    pub fn set_confirmation_deposit(&mut self, amount: U128) {
+        self.assert_owner();
+        self.take_confirmation_deposit();
+        if amount.0 == 0 {
+            TokenError::ZeroConfirmationDeposit.panic();
+        }
+        self.confirmation_deposit = amount.into();
+    }
+
+    /**
+     * Check the caller attached exactly the confirmation deposit.
+     *
+     * The deposit is added to the storage stake of the caller,
+     * where it can be taken back with storage_withdraw(). Unregistered callers get it refunded.
+     */
+    pub(crate) fn take_confirmation_deposit(&mut self) {
+        if env::attached_deposit() != self.confirmation_deposit {
+            TokenError::ConfirmationDeposit { required: self.confirmation_deposit }.panic();
+        }
+        self.take_attached_deposit();
+    }
+
+    /// Add whatever the caller attached to its storage stake, or refund it if the caller is not registered
+    pub(crate) fn take_attached_deposit(&mut self) {
+        let deposit = env::attached_deposit();
+        if deposit == 0 {
+            return;
+        }
+        let account_id = env::predecessor_account_id();
+        if self.ledger.is_registered(&account_id) {
+            self.ledger.add_storage_deposit(&account_id, deposit).unwrap_or_panic();
+        } else {
+            Promise::new(account_id).transfer(deposit);
+        }
+    }
+
+    pub(crate) fn assert_owner(&self) {
+        if env::predecessor_account_id() != self.owner_id {
+            TokenError::NotOwner.panic();
+        }
+    }
+
+    /// Returns accounts that can mint and burn tokens
+    pub fn get_minters(&self) -> Vec<AccountId> {
+        self.minters.clone()
+    }
+
+    /// Allow `account_id` to mint and burn tokens. Only the owner can do this.
+    #[payable]
+    pub fn add_minter(&mut self, account_id: AccountId) {
+        self.assert_owner();
+        self.take_confirmation_deposit();
+        assert_valid_account_id(&account_id).unwrap_or_panic();
+        if !self.minters.contains(&account_id) {
+            self.minters.push(account_id);
+        }
+    }
+
+    /// Remove the minter role from `account_id`. Only the owner can do this.
+    #[payable]
+    pub fn remove_minter(&mut self, account_id: AccountId) {
+        self.assert_owner();
+        self.take_confirmation_deposit();
+        self.minters.retain(|minter| *minter != account_id);
+    }
+
+    fn assert_minter(&self) {
+        if !self.minters.contains(&env::predecessor_account_id()) {
+            TokenError::NotMinter.panic();
+        }
+    }
+
+    /// Create new tokens to `account_id`. Only minters can do this.
+    #[payable]
+    pub fn mint(&mut self, account_id: AccountId, amount: Balance) {
+        self.assert_minter();
+        self.take_confirmation_deposit();
+        self.ledger.mint(&account_id, amount, &env::predecessor_account_id()).unwrap_or_panic();
+    }
+
+    /// Destroy tokens of the caller
+    #[payable]
+    pub fn burn(&mut self, amount: Balance) {
+        self.take_confirmation_deposit();
+        let account_id = env::predecessor_account_id();
+        self.ledger.burn(&account_id, amount, &account_id).unwrap_or_panic();
+    }
+
+    /// Destroy tokens of `account_id`. Only minters can do this.
+    #[payable]
+    pub fn burn_from(&mut self, account_id: AccountId, amount: Balance) {
+        self.assert_minter();
+        self.take_confirmation_deposit();
+        self.ledger.burn(&account_id, amount, &env::predecessor_account_id()).unwrap_or_panic();
+    }
+
+    /// Roll back a transfer whose promise chain did not complete in time.
+    /// Anyone can call this, the tokens always return to the original sender.
+    pub fn reclaim_expired(&mut self, transfer_id: TransferId) {
+        self.ledger.reclaim_expired(transfer_id).unwrap_or_panic();
+    }
+
+    /// Send owner's tokens to another person or a smart contract.
+    /// Returns the transfer id that can be used to follow the promise chain.
+    #[payable]
+    pub fn send(&mut self, new_owner_id: AccountId, amount: Balance, message: Vec<u8>) -> TransferId {
+        self.take_confirmation_deposit();
+        self.ledger.send(env::predecessor_account_id(), new_owner_id, amount, message).unwrap_or_panic()
     }
 
-    /// Send owner's tokens to another person or a smart contract
+    /**
+     * Send owner's tokens to several accounts at once.
+     *
+     * Each recipient gets its own transfer id and is notified like with send(),
+     * in its own promise chain that is finalised or rolled back independently.
+     *
+     * Every recipient needs BATCH_PROMISE_GAS + BATCH_CHECK_GAS + BATCH_HANDLE_GAS,
+     * 70 Tgas, of the attached gas, and its contract gets 10 Tgas for on_token_received().
+     * With the 300 Tgas limit of a transaction one call can send to at most 4 recipients.
+     * Use pay_batch() for payouts that do not need to notify the recipients.
+     */
+    #[payable]
+    pub fn send_batch(&mut self, transfers: Vec<(AccountId, U128, Vec<u8>)>) -> Vec<TransferId> {
+        self.take_confirmation_deposit();
+        check_batch_gas(transfers.len(), BATCH_PROMISE_GAS + BATCH_CHECK_GAS + BATCH_HANDLE_GAS).unwrap_or_panic();
+
+        let transfers = transfers
+            .into_iter()
+            .map(|(new_owner_id, amount, message)| (new_owner_id, amount.into(), message))
+            .collect();
+        self.ledger.send_batch(env::predecessor_account_id(), transfers).unwrap_or_panic()
+    }
+
+    /**
+     * Pay owner's tokens to several accounts at once, without notifying them.
+     *
+     * This is an explicit opt-out of the receiver notification: the transfers are finalised at once,
+     * like ft_transfer(), and contracts among the recipients are not told they received tokens.
+     * Use send_batch() when the recipients need to know.
+     *
+     * Every payout only writes the ledger and needs BATCH_PAYOUT_GAS, 5 Tgas,
+     * so one call can pay at most 59 recipients. Every transfer also logs its events and a call can log 16 kB,
+     * which caps a batch at about 40 recipients with ordinary account ids.
+     * Larger payouts must be split into several calls.
+     */
     #[payable]
-    pub fn send(&mut self, new_owner_id: AccountId, amount: Balance, message: Vec<u8>) {
-        self.ledger.send(env::predecessor_account_id(), new_owner_id, amount, message);
+    pub fn pay_batch(&mut self, transfers: Vec<(AccountId, U128)>) -> Vec<TransferId> {
+        self.take_confirmation_deposit();
+        check_batch_gas(transfers.len(), BATCH_PAYOUT_GAS).unwrap_or_panic();
+
+        let transfers = transfers
+            .into_iter()
+            .map(|(new_owner_id, amount)| (new_owner_id, amount.into()))
+            .collect();
+        self.ledger.pay_batch(env::predecessor_account_id(), transfers).unwrap_or_panic()
     }
 
     /**
@@ -317,13 +1536,15 @@ impl Token {
      * We gpt the interface test promise back. If the account was not smart contract, finalise the transaction.
      * Otherwise trigger the smart contract notifier.
      */
-    pub fn handle_receiver(&mut self, old_owner_id: AccountId, new_owner_id: AccountId, amount_received: U128, amount_total: U128, message: Vec<u8>) {
+    pub fn handle_receiver(&mut self, transfer_id: TransferId, amount_total: U128, message: Vec<u8>) {
         // Only callable by self
         assert_eq!(env::current_account_id(), env::predecessor_account_id());
         env::log(b"handle_receiver reached");
 
-        let uint_amount_received: u128 = amount_received.into();
-        let uint_amount_total: u128 = amount_total.into();
+        if self.ledger.get_transfer(transfer_id).unwrap_or_panic().status != TransferStatus::Pending {
+            env::log(format!("Transfer {} was already reclaimed", transfer_id).as_bytes());
+            return;
+        }
 
         if is_promise_success() {
 
@@ -333,17 +1554,24 @@ impl Token {
 
             env::log(b"Constructing smart contract notifier promise");
 
+            let transfer = self.ledger.get_transfer(transfer_id).unwrap_or_panic();
+            let amount_received = U128::from(transfer.amount);
+
+            // The receiver and our callback share the gas this call got
+            let notify_gas = (env::prepaid_gas() - env::used_gas()).saturating_sub(HANDLE_RECEIVER_GAS) / 2;
+
+            let args = OnTokenReceivedArgs {
+                sender_id: transfer.sender_id,
+                amount_received: amount_received.into(),
+                amount_total: amount_total.into(),
+                message,
+            };
             let promise0 = env::promise_create(
-                new_owner_id.clone(),
-                b"on_token_received",
-                json!({
-                    "sender_id": old_owner_id,
-                    "amount_received": amount_received,
-                    "amount_total": amount_total,
-                    "message": message,
-                }).to_string().as_bytes(),
+                transfer.receiver_id,
+                ON_TOKEN_RECEIVED_METHOD.as_bytes(),
+                &serde_json::to_vec(&args).unwrap(),
                 0,
-                SINGLE_CALL_GAS/10,
+                notify_gas,
             );
 
             // Construct the promise that calls back the
@@ -353,37 +1581,100 @@ impl Token {
                 env::current_account_id(),
                 b"handle_token_received",
                 json!({
-                    "old_owner_id": old_owner_id,
-                    "new_owner_id": new_owner_id,
-                    "amount_received": amount_received,
+                    "transfer_id": transfer_id,
                 }).to_string().as_bytes(),
                 0,
-                SINGLE_CALL_GAS/10,
+                notify_gas,
             );
 
             env::promise_return(promise1);
         } else {
             // Non-code account
             // Finalise transaction now.
-            self.ledger.finalise(new_owner_id, uint_amount_received);
+            self.ledger.finalise(transfer_id).unwrap_or_panic();
         }
     }
 
-    /// Smart contract notify succeed, free up any locked balance
-    /// TODO: Add functionality so that the smart contract that received tokens can trigger a new promise chain here
-    pub fn handle_token_received(&mut self, old_owner_id: AccountId, new_owner_id: AccountId, amount_received: U128) {
+    /**
+     * The receiving smart contract has processed the incoming tokens.
+     *
+     * Finalise, partially finalise or roll back the transaction based on the ReceiverResult
+     * the smart contract returned. A failed or unreadable result is handled as a rejection.
+     *
+     * The smart contract can also ask the tokens to be forwarded in a new promise chain.
+     * Then this transaction stays locked until the follow-up transaction completes.
+     * The follow-up chain gets the gas left in this call. With less than NOTIFY_GAS left
+     * the forward is rejected, which happens e.g. after a send_batch() or a second forward.
+     * The forwarding contract pays for the follow-up transfer, so the forward is also rejected
+     * if its storage stake cannot cover it.
+     */
+    pub fn handle_token_received(&mut self, transfer_id: TransferId) {
         // Only callable by self
         assert_eq!(env::current_account_id(), env::predecessor_account_id());
         env::log(b"Checking for the need to rollback smart contract transaction");
 
-        let amount_received: u128 = amount_received.into();
+        if self.ledger.get_transfer(transfer_id).unwrap_or_panic().status != TransferStatus::Pending {
+            env::log(format!("Transfer {} was already reclaimed", transfer_id).as_bytes());
+            return;
+        }
 
-        // TODO: Have some nice error code logic here
-        if is_promise_success() {
-            self.ledger.finalise(new_owner_id, amount_received);
-        } else {
-            self.ledger.rollback(old_owner_id, new_owner_id, amount_received);
+        let result = match get_promise_result::<ReceiverResult>() {
+            Some(Ok(result)) => result,
+            Some(Err(e)) => ReceiverResult::Rejected {
+                code: REJECT_INVALID_RESULT,
+                reason: format!("Could not decode receiver result: {}", e),
+            },
+            None => ReceiverResult::Rejected {
+                code: REJECT_RECEIVER_FAILED,
+                reason: String::from("Receiver call failed"),
+            },
+        };
+
+        // Partial or forwarded amount cannot be more than what was sent
+        let transfer = self.ledger.get_transfer(transfer_id).unwrap_or_panic();
+        let amount = transfer.amount;
+        let result = match result {
+            ReceiverResult::AcceptedPartial { amount_used } if amount_used.0 > amount => ReceiverResult::Rejected {
+                code: REJECT_INVALID_RESULT,
+                reason: format!("Receiver used {} tokens, but received only {}", amount_used.0, amount),
+            },
+            ReceiverResult::Forward { amount: forwarded, .. } if forwarded.0 == 0 || forwarded.0 > amount => ReceiverResult::Rejected {
+                code: REJECT_INVALID_RESULT,
+                reason: format!("Receiver forwarded {} tokens, but received {}", forwarded.0, amount),
+            },
+            ReceiverResult::Forward { receiver_id, .. } if receiver_id == transfer.receiver_id || !self.ledger.is_registered(&receiver_id) => ReceiverResult::Rejected {
+                code: REJECT_INVALID_RESULT,
+                reason: format!("Receiver cannot forward tokens to {}", receiver_id),
+            },
+            ReceiverResult::Forward { .. } if env::prepaid_gas() - env::used_gas() < NOTIFY_GAS => ReceiverResult::Rejected {
+                code: REJECT_INVALID_RESULT,
+                reason: format!("Not enough gas to forward tokens, need {}", NOTIFY_GAS),
+            },
+            ReceiverResult::Forward { .. } if self.ledger.check_storage_available(&transfer.receiver_id, LOCK_STORAGE_USAGE).is_err() => ReceiverResult::Rejected {
+                code: REJECT_INVALID_RESULT,
+                reason: format!("{} needs a storage deposit to forward tokens", transfer.receiver_id),
+            },
+            result => result,
+        };
+
+        match &result {
+            ReceiverResult::Accepted => {
+                self.ledger.finalise(transfer_id).unwrap_or_panic();
+            },
+            ReceiverResult::Rejected { code, reason } => {
+                env::log(format!("Receiver rejected transfer {} with code {}: {}", transfer_id, code, reason).as_bytes());
+                self.ledger.rollback(transfer_id).unwrap_or_panic();
+            },
+            ReceiverResult::AcceptedPartial { amount_used } => {
+                self.ledger.finalise_partial(transfer_id, amount_used.0).unwrap_or_panic();
+            },
+            ReceiverResult::Forward { receiver_id, amount, message } => {
+                let follow_up_id = self.ledger.forward(transfer_id, receiver_id.clone(), amount.0, message.clone()).unwrap_or_panic();
+                env::log(format!("Transfer {} forwarded as transfer {}", transfer_id, follow_up_id).as_bytes());
+            },
         }
+
+        self.ledger.set_transfer_result(transfer_id, result).unwrap_or_panic();
     }
 }
 
@@ -392,50 +1683,834 @@ impl Token {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use near_sdk::MockedBlockchain;
-    use near_sdk::{testing_env, VMContext};
+    use near_sdk::PromiseResult;
+    use nep9000_testing::*;
+
+    use crate::test_utils::{ register_accounts, STAKE };
+    use std::collections::HashMap;
+
+    fn receiver_result(result: &ReceiverResult) -> PromiseResult {
+        PromiseResult::Successful(serde_json::to_vec(result).unwrap())
+    }
+
+    #[test]
+    fn test_new() {
+        let context = get_deposit_context(carol(), 1);
+        set_context(context);
+        let total_supply = 1_000_000_000_000_000u128;
+        let contract = Token::new(bob(), total_supply, None, None, None);
+        assert_eq!(contract.get_total_supply(), total_supply);
+        assert_eq!(contract.get_balance(bob()), total_supply);
+    }
+
+    #[test]
+    fn test_rollback_releases_only_own_lock() {
+        let context = get_deposit_context(bob(), 1);
+        set_context(context);
+        let total_supply = 1_000_000_000_000_000u128;
+        let mut contract = Token::new(bob(), total_supply, None, None, None);
+        register_accounts(&mut contract);
+
+        let first = contract.ledger.send(bob(), carol(), 100, vec![]).unwrap_or_panic();
+        let second = contract.ledger.send(bob(), carol(), 200, vec![]).unwrap_or_panic();
+        assert_ne!(first, second);
+        assert_eq!(contract.get_locked_balance(carol()), 300);
+
+        contract.ledger.rollback(first).unwrap_or_panic();
+        assert_eq!(contract.get_locked_balance(carol()), 200);
+        assert_eq!(contract.get_balance(carol()), 200);
+        assert_eq!(contract.get_balance(bob()), total_supply - 200);
+        assert_eq!(contract.get_rollback_count(), 1);
+
+        contract.ledger.finalise(second).unwrap_or_panic();
+        assert_eq!(contract.get_locked_balance(carol()), 0);
+        assert_eq!(contract.get_balance(carol()), 200);
+    }
+
+    #[test]
+    fn test_transfer_status() {
+        let context = get_deposit_context(bob(), 1);
+        set_context(context);
+        let total_supply = 1_000_000_000_000_000u128;
+        let mut contract = Token::new(bob(), total_supply, None, None, None);
+        register_accounts(&mut contract);
+
+        let first = contract.send(carol(), 100, vec![]);
+        let second = contract.send(carol(), 200, vec![]);
+        let third = contract.send(carol(), 300, vec![]);
+
+        let pending = contract.get_pending_transfers(carol(), 0, 10);
+        assert_eq!(pending.len(), 3);
+        assert_eq!(pending[0].transfer_id, first);
+        assert_eq!(pending[0].sender_id, bob());
+        assert_eq!(pending[0].status, TransferStatus::Pending);
+        assert_eq!(contract.get_pending_transfers(bob(), 1, 1)[0].transfer_id, second);
+
+        // The last transfer takes the place of the completed one
+        contract.ledger.finalise(first).unwrap_or_panic();
+        let ids: Vec<TransferId> = contract.get_pending_transfers(bob(), 0, 10).iter().map(|t| t.transfer_id).collect();
+        assert_eq!(ids, vec![third, second]);
+        assert!(!contract.ledger.is_pending_for(&carol(), first));
+
+        contract.ledger.rollback(second).unwrap_or_panic();
+        contract.ledger.finalise(third).unwrap_or_panic();
+
+        assert!(contract.get_pending_transfers(bob(), 0, 10).is_empty());
+        assert_eq!(contract.get_transfer(first).unwrap().status, TransferStatus::Finalised);
+        assert_eq!(contract.get_transfer(second).unwrap().status, TransferStatus::RolledBack);
+        assert!(contract.get_transfer(third + 1).is_none());
+    }
+
+    #[test]
+    fn test_reclaim_expired() {
+        set_context(get_deposit_context(bob(), 1));
+        let mut contract = Token::new(bob(), 1_000u128, None, None, Some(10));
+        register_accounts(&mut contract);
+        let transfer_id = contract.send(carol(), 100, vec![]);
+        assert_event("transfer_started");
+
+        advance_blocks(10);
+        contract.reclaim_expired(transfer_id);
+        assert_event("transfer_rolled_back");
+        assert_eq!(contract.get_balance(bob()), 1_000);
+        assert_eq!(contract.get_balance(carol()), 0);
+        assert_eq!(contract.get_locked_balance(carol()), 0);
+        assert_eq!(contract.get_transfer(transfer_id).unwrap().status, TransferStatus::RolledBack);
+    }
+
+    #[test]
+    #[should_panic(expected = "is locked until block 10")]
+    fn test_cannot_reclaim_before_expiry() {
+        let mut context = get_deposit_context(bob(), 1);
+        set_context(context.clone());
+        let mut contract = Token::new(bob(), 1_000u128, None, None, Some(10));
+        register_accounts(&mut contract);
+        let transfer_id = contract.send(carol(), 100, vec![]);
+
+        context.block_index = 9;
+        set_context(context);
+        contract.reclaim_expired(transfer_id);
+    }
+
+    #[test]
+    #[should_panic(expected = "is no longer pending")]
+    fn test_cannot_rollback_twice() {
+        let context = get_deposit_context(bob(), 1);
+        set_context(context);
+        let mut contract = Token::new(bob(), 1_000u128, None, None, None);
+        register_accounts(&mut contract);
+        let transfer_id = contract.send(carol(), 100, vec![]);
+        contract.ledger.rollback(transfer_id).unwrap_or_panic();
+        contract.ledger.rollback(transfer_id).unwrap_or_panic();
+    }
+
+
+    #[test]
+    fn test_receiver_rejects() {
+        set_context(get_deposit_context(bob(), 1));
+        let mut contract = Token::new(bob(), 1_000u128, None, None, None);
+        register_accounts(&mut contract);
+        let transfer_id = contract.send(carol(), 100, vec![]);
+
+        let rejected = ReceiverResult::Rejected { code: 1001, reason: String::from("Pool closed") };
+        set_callback_context(get_context(alice()), receiver_result(&rejected));
+        contract.handle_token_received(transfer_id);
+
+        let transfer = contract.get_transfer(transfer_id).unwrap();
+        assert_eq!(transfer.status, TransferStatus::RolledBack);
+        assert_eq!(transfer.result, Some(rejected));
+        assert_eq!(contract.get_balance(bob()), 1_000);
+        assert_eq!(contract.get_balance(carol()), 0);
+        assert_logged("Receiver rejected transfer 0 with code 1001: Pool closed");
+    }
+
+    #[test]
+    fn test_holders() {
+        set_context(get_deposit_context(bob(), 1));
+        let mut contract = Token::new(bob(), 1_000u128, None, None, None);
+        register_accounts(&mut contract);
+        assert_eq!(contract.get_holder_count(), 1);
+
+        let transfer_id = contract.send(carol(), 1_000, vec![]);
+        set_callback_context(get_context(alice()), PromiseResult::Failed);
+        contract.handle_receiver(transfer_id, 1_000.into(), vec![]);
+
+        // Bob sold everything, so only Carol holds tokens
+        assert_eq!(contract.get_holder_count(), 1);
+        assert_eq!(contract.get_holders(0, 10), vec![HolderView { account_id: carol(), balance: 1_000.into() }]);
+
+        set_context(get_deposit_context(bob(), 1));
+        contract.add_minter(bob());
+        contract.mint(alice(), 500);
+        contract.mint(bob(), 500);
+        assert_eq!(contract.get_holder_count(), 3);
+        assert_eq!(contract.get_holders(1, 10).len(), 2);
+        assert_eq!(contract.get_holders(3, 10).len(), 0);
+
+        let check = contract.get_supply_check();
+        assert!(check.matches);
+        assert_eq!(check.balance_sum, 2_000.into());
+    }
+
+    #[test]
+    fn test_balance_checkpoints() {
+        set_context(get_deposit_context(bob(), 1));
+        let mut contract = Token::new(bob(), 1_000u128, None, None, None);
+        register_accounts(&mut contract);
+        contract.add_minter(bob());
+
+        let mut context = get_deposit_context(bob(), 1);
+        context.block_index = 10;
+        set_context(context);
+        contract.mint(carol(), 500);
+
+        let mut context = get_deposit_context(carol(), 1);
+        context.block_index = 20;
+        set_context(context);
+        contract.burn(200);
+
+        assert_eq!(contract.get_balance_at(carol(), 9), 0);
+        assert_eq!(contract.get_balance_at(carol(), 10), 500);
+        assert_eq!(contract.get_balance_at(carol(), 19), 500);
+        assert_eq!(contract.get_balance_at(carol(), 20), 300);
+        assert_eq!(contract.get_balance_at(bob(), 0), 1_000);
+        assert_eq!(contract.get_total_supply_at(0), 1_000);
+        assert_eq!(contract.get_total_supply_at(15), 1_500);
+        assert_eq!(contract.get_total_supply_at(25), 1_300);
+    }
 
-    fn alice() -> AccountId {
-        "alice.near".to_string()
+    #[test]
+    fn test_balance_history_is_charged_to_the_sender() {
+        set_context(get_deposit_context(bob(), 1));
+        let mut contract = Token::new(bob(), 1_000u128, None, None, None);
+        contract.ledger.add_storage_deposit(&bob(), STAKE).unwrap_or_panic();
+        contract.ledger.register(&carol(), 0).unwrap_or_panic();
+        let cost = contract.ledger.get_storage_cost();
+        let initial = contract.ledger.get_required_storage_deposit(&bob());
+
+        contract.ft_transfer(carol(), 100.into(), None);
+        let first = contract.ledger.get_required_storage_deposit(&bob());
+        assert!(first > initial);
+
+        // Changes within a block replace the last checkpoint
+        contract.ft_transfer(carol(), 100.into(), None);
+        assert_eq!(contract.ledger.get_required_storage_deposit(&bob()), first);
+
+        advance_blocks(1);
+        contract.ft_transfer(carol(), 100.into(), None);
+        assert!(contract.ledger.get_required_storage_deposit(&bob()) > first);
+
+        // The receiver pays nothing for the history the sender writes
+        assert_eq!(contract.ledger.get_required_storage_deposit(&carol()), cost);
     }
 
-    fn bob() -> AccountId {
-        "bob.near".to_string()
+    #[test]
+    fn test_send_batch_rolls_back_per_recipient() {
+        set_context(get_deposit_context(bob(), 1));
+        let mut contract = Token::new(bob(), 1_000u128, None, None, None);
+        register_accounts(&mut contract);
+        let transfer_ids = contract.send_batch(vec![
+            (carol(), 100.into(), vec![2]),
+            (alice(), 200.into(), vec![]),
+        ]);
+        assert_eq!(transfer_ids.len(), 2);
+        assert_eq!(contract.get_balance(bob()), 700);
+        assert_eq!(contract.get_locked_balance(carol()), 100);
+
+        // Recipients without a message are notified too
+        assert_eq!(contract.get_transfer(transfer_ids[1]).unwrap().status, TransferStatus::Pending);
+        assert_eq!(contract.get_locked_balance(alice()), 200);
+
+        set_callback_context(get_context(alice()), PromiseResult::Failed);
+        contract.handle_token_received(transfer_ids[0]);
+        set_callback_context(get_context(alice()), receiver_result(&ReceiverResult::Accepted));
+        contract.handle_token_received(transfer_ids[1]);
+
+        assert_eq!(contract.get_transfer(transfer_ids[0]).unwrap().status, TransferStatus::RolledBack);
+        assert_eq!(contract.get_transfer(transfer_ids[1]).unwrap().status, TransferStatus::Finalised);
+        assert_eq!(contract.get_balance(bob()), 800);
+        assert_eq!(contract.get_balance(carol()), 0);
+        assert_eq!(contract.get_balance(alice()), 200);
     }
 
-    fn carol() -> AccountId {
-        "carol.near".to_string()
+    #[test]
+    #[should_panic(expected = "Not enough balance, need 1100, has 1000")]
+    fn test_send_batch_checks_total() {
+        set_context(get_deposit_context(bob(), 1));
+        let mut contract = Token::new(bob(), 1_000u128, None, None, None);
+        register_accounts(&mut contract);
+        contract.send_batch(vec![
+            (carol(), 600.into(), vec![]),
+            (alice(), 500.into(), vec![]),
+        ]);
     }
 
-    fn get_context(predecessor_account_id: AccountId) -> VMContext {
-        VMContext {
-            current_account_id: alice(),
-            signer_account_id: bob(),
-            signer_account_pk: vec![0, 1, 2],
-            predecessor_account_id,
-            input: vec![],
-            block_index: 0,
-            block_timestamp: 0,
-            account_balance: 1_000_000_000_000_000_000_000_000_000u128,
-            account_locked_balance: 0,
-            storage_usage: 10u64.pow(6),
-            attached_deposit: 0,
-            prepaid_gas: 10u64.pow(18),
-            random_seed: vec![0, 1, 2],
-            is_view: false,
-            output_data_receivers: vec![],
-            epoch_height: 0,
+    /// Token owned by bob with `count` registered recipients, and the context of a transaction with 300 Tgas
+    fn setup_batch(count: usize) -> (Token, Vec<AccountId>) {
+        set_context(get_deposit_context(bob(), 1));
+        let mut contract = Token::new(bob(), 10_000u128, None, None, None);
+        contract.ledger.add_storage_deposit(&bob(), STAKE).unwrap_or_panic();
+        let mut recipients = vec![];
+        for i in 0..count {
+            let account_id = format!("recipient{}.near", i);
+            contract.ledger.register_sponsored(&account_id, 0).unwrap_or_panic();
+            recipients.push(account_id);
         }
+        set_context(ContextBuilder::new().predecessor_account_id(bob()).attached_deposit(1).prepaid_gas(300_000_000_000_000).build());
+        (contract, recipients)
+    }
+
+    /// Call send_batch() from bob with the 300 Tgas of a transaction, sending 10 tokens to each of `count` recipients
+    fn send_batch_in_transaction(count: usize) -> (Token, Vec<TransferId>) {
+        let (mut contract, recipients) = setup_batch(count);
+        let transfer_ids = contract.send_batch(recipients.into_iter().map(|a| (a, 10.into(), vec![1])).collect());
+        (contract, transfer_ids)
+    }
+
+    /// Call pay_batch() from bob with the 300 Tgas of a transaction, paying 10 tokens to each of `count` recipients
+    fn pay_batch_in_transaction(count: usize) -> (Token, Vec<TransferId>) {
+        let (mut contract, recipients) = setup_batch(count);
+        let transfer_ids = contract.pay_batch(recipients.into_iter().map(|a| (a, 10.into())).collect());
+        (contract, transfer_ids)
     }
 
     #[test]
-    fn test_new() {
-        let context = get_context(carol());
-        testing_env!(context);
-        let total_supply = 1_000_000_000_000_000u128;
-        let contract = Token::new(bob(), total_supply.into());
-        assert_eq!(contract.get_total_supply(), total_supply);
-        assert_eq!(contract.get_balance(bob()), total_supply);
+    fn test_send_batch_gas_limits() {
+        let (contract, transfer_ids) = send_batch_in_transaction(4);
+        assert_eq!(transfer_ids.len(), 4);
+        assert_eq!(contract.get_transfer(transfer_ids[3]).unwrap().status, TransferStatus::Pending);
+        assert_eq!(contract.get_locked_balance(String::from("recipient3.near")), 10);
+        assert_eq!(contract.get_balance(bob()), 10_000 - 40);
+    }
+
+    #[test]
+    fn test_pay_batch() {
+        let (contract, transfer_ids) = pay_batch_in_transaction(40);
+        assert_eq!(transfer_ids.len(), 40);
+        assert_eq!(contract.get_transfer(transfer_ids[39]).unwrap().status, TransferStatus::Finalised);
+        assert_eq!(contract.get_locked_balance(String::from("recipient39.near")), 0);
+        assert_eq!(contract.get_balance(String::from("recipient39.near")), 10);
+        assert_eq!(contract.get_balance(bob()), 10_000 - 400);
+    }
+
+    #[test]
+    #[should_panic(expected = "Not enough gas, need 350000000000000")]
+    fn test_send_batch_checks_gas() {
+        send_batch_in_transaction(5);
+    }
+
+    #[test]
+    #[should_panic(expected = "Not enough gas, need 300000000000000")]
+    fn test_pay_batch_checks_gas() {
+        pay_batch_in_transaction(60);
+    }
+
+    #[test]
+    fn test_receiver_accepts_partial() {
+        set_context(get_deposit_context(bob(), 1));
+        let mut contract = Token::new(bob(), 1_000u128, None, None, None);
+        register_accounts(&mut contract);
+        let transfer_id = contract.send(carol(), 100, vec![]);
+
+        set_callback_context(get_context(alice()), receiver_result(&ReceiverResult::AcceptedPartial { amount_used: 60.into() }));
+        contract.handle_token_received(transfer_id);
+
+        let transfer = contract.get_transfer(transfer_id).unwrap();
+        assert_eq!(transfer.status, TransferStatus::Finalised);
+        assert_eq!(transfer.refunded, 40.into());
+        assert_eq!(contract.get_rollback_count(), 0);
+        assert_eq!(contract.get_balance(bob()), 940);
+        assert_eq!(contract.get_balance(carol()), 60);
+        assert_eq!(contract.get_locked_balance(carol()), 0);
+    }
+
+    #[test]
+    fn test_unreadable_receiver_result_rolls_back() {
+        set_context(get_deposit_context(bob(), 1));
+        let mut contract = Token::new(bob(), 1_000u128, None, None, None);
+        register_accounts(&mut contract);
+        let transfer_id = contract.send(carol(), 100, vec![]);
+
+        set_callback_context(get_context(alice()), PromiseResult::Successful(b"null".to_vec()));
+        contract.handle_token_received(transfer_id);
+
+        let transfer = contract.get_transfer(transfer_id).unwrap();
+        assert_eq!(transfer.status, TransferStatus::RolledBack);
+        match transfer.result {
+            Some(ReceiverResult::Rejected { code, .. }) => assert_eq!(code, REJECT_INVALID_RESULT),
+            _ => panic!("Expected rejection"),
+        }
+    }
+
+    #[test]
+    fn test_forward_finalises_after_follow_up() {
+        set_context(get_deposit_context(bob(), 1));
+        let mut contract = Token::new(bob(), 1_000u128, None, None, None);
+        register_accounts(&mut contract);
+        let transfer_id = contract.send(carol(), 100, vec![]);
+
+        let forward = ReceiverResult::Forward { receiver_id: alice(), amount: 70.into(), message: vec![1] };
+        set_callback_context(get_context(alice()), receiver_result(&forward));
+        contract.handle_token_received(transfer_id);
+
+        let follow_up = contract.get_pending_transfers(alice(), 0, 10);
+        assert_eq!(follow_up.len(), 1);
+        assert_eq!(follow_up[0].parent_id, Some(transfer_id));
+        assert_eq!(contract.get_transfer(transfer_id).unwrap().status, TransferStatus::Pending);
+        assert_eq!(contract.get_balance(carol()), 30);
+        assert_eq!(contract.get_locked_balance(carol()), 30);
+        assert_eq!(contract.get_locked_balance(alice()), 70);
+
+        set_callback_context(get_context(alice()), receiver_result(&ReceiverResult::Accepted));
+        contract.handle_token_received(follow_up[0].transfer_id);
+
+        assert_eq!(contract.get_transfer(transfer_id).unwrap().status, TransferStatus::Finalised);
+        assert_eq!(contract.get_locked_balance(carol()), 0);
+        assert_eq!(contract.get_locked_balance(alice()), 0);
+        assert_eq!(contract.get_balance(alice()), 70);
+    }
+
+    #[test]
+    fn test_forward_rolls_back_whole_chain() {
+        set_context(get_deposit_context(bob(), 1));
+        let mut contract = Token::new(bob(), 1_000u128, None, None, None);
+        register_accounts(&mut contract);
+        let transfer_id = contract.send(carol(), 100, vec![]);
+
+        let forward = ReceiverResult::Forward { receiver_id: alice(), amount: 100.into(), message: vec![] };
+        set_callback_context(get_context(alice()), receiver_result(&forward));
+        contract.handle_token_received(transfer_id);
+        let follow_up_id = contract.get_pending_transfers(alice(), 0, 10)[0].transfer_id;
+
+        set_callback_context(get_context(alice()), PromiseResult::Failed);
+        contract.handle_token_received(follow_up_id);
+
+        assert_eq!(contract.get_transfer(follow_up_id).unwrap().status, TransferStatus::RolledBack);
+        assert_eq!(contract.get_transfer(transfer_id).unwrap().status, TransferStatus::RolledBack);
+        assert_eq!(contract.get_balance(bob()), 1_000);
+        assert_eq!(contract.get_balance(carol()), 0);
+        assert_eq!(contract.get_balance(alice()), 0);
+        assert_eq!(contract.get_locked_balance(carol()), 0);
+        assert_eq!(contract.get_locked_balance(alice()), 0);
+    }
+
+    #[test]
+    fn test_forward_without_gas_rolls_back() {
+        set_context(get_deposit_context(bob(), 1));
+        let mut contract = Token::new(bob(), 1_000u128, None, None, None);
+        register_accounts(&mut contract);
+        let transfer_id = contract.send(carol(), 100, vec![]);
+
+        let forward = ReceiverResult::Forward { receiver_id: alice(), amount: 70.into(), message: vec![] };
+        set_callback_context(ContextBuilder::new().predecessor_account_id(alice()).prepaid_gas(NOTIFY_GAS - 1).build(), receiver_result(&forward));
+        contract.handle_token_received(transfer_id);
+
+        let transfer = contract.get_transfer(transfer_id).unwrap();
+        assert_eq!(transfer.status, TransferStatus::RolledBack);
+        assert_eq!(transfer.result, Some(ReceiverResult::Rejected {
+            code: REJECT_INVALID_RESULT,
+            reason: format!("Not enough gas to forward tokens, need {}", NOTIFY_GAS),
+        }));
+        assert_eq!(contract.get_balance(bob()), 1_000);
+        assert_eq!(contract.get_balance(alice()), 0);
+    }
+
+    #[test]
+    fn test_forward_without_storage_deposit_rolls_back() {
+        set_context(get_deposit_context(bob(), 1));
+        let mut contract = Token::new(bob(), 1_000u128, None, None, None);
+        register_accounts(&mut contract);
+        let dave = String::from("dave.near");
+        let cost = contract.ledger.get_storage_cost();
+        contract.ledger.register(&dave, cost).unwrap_or_panic();
+        let transfer_id = contract.send(dave.clone(), 100, vec![]);
+
+        // dave would pay for the follow-up transfer, but only staked for its account
+        let forward = ReceiverResult::Forward { receiver_id: alice(), amount: 70.into(), message: vec![] };
+        set_callback_context(get_context(alice()), receiver_result(&forward));
+        contract.handle_token_received(transfer_id);
+
+        let transfer = contract.get_transfer(transfer_id).unwrap();
+        assert_eq!(transfer.status, TransferStatus::RolledBack);
+        assert_eq!(transfer.result, Some(ReceiverResult::Rejected {
+            code: REJECT_INVALID_RESULT,
+            reason: String::from("dave.near needs a storage deposit to forward tokens"),
+        }));
+        assert_eq!(contract.get_balance(bob()), 1_000);
+        assert_eq!(contract.get_balance(alice()), 0);
+        assert!(contract.get_pending_transfers(alice(), 0, 10).is_empty());
+    }
+
+    #[test]
+    fn test_update_metadata() {
+        set_context(get_deposit_context(bob(), 1));
+        let mut contract = Token::new(bob(), 1_000u128, None, None, None);
+        register_accounts(&mut contract);
+        assert_eq!(contract.get_owner(), bob());
+        assert_eq!(contract.get_name(), "");
+
+        let metadata = Metadata {
+            name: String::from("Advanced Fungible"),
+            symbol: String::from("NEP9000"),
+            web_link: String::from("https://example.com"),
+            metadata_link: String::from(""),
+            decimals: 18,
+            icon: Some(String::from("data:image/svg+xml,<svg></svg>")),
+            metadata_hash: None,
+        };
+        contract.update_metadata(metadata);
+        assert_eq!(contract.get_name(), "Advanced Fungible");
+        assert_eq!(contract.get_metadata().symbol, "NEP9000");
+        assert_eq!(contract.get_metadata().decimals, 18);
+    }
+
+    #[test]
+    #[should_panic(expected = "Icon must be a data URL")]
+    fn test_icon_must_be_inline() {
+        set_context(get_deposit_context(bob(), 1));
+        let metadata = Metadata {
+            icon: Some(String::from("https://example.com/icon.png")),
+            ..Default::default()
+        };
+        Token::new(bob(), 1_000u128, Some(metadata), None, None);
+    }
+
+
+    #[test]
+    #[should_panic(expected = "Only the owner can call this method")]
+    fn test_only_owner_updates_metadata() {
+        set_context(get_deposit_context(bob(), 1));
+        let mut contract = Token::new(bob(), 1_000u128, None, None, None);
+        register_accounts(&mut contract);
+        set_context(get_deposit_context(carol(), 1));
+        contract.update_metadata(Metadata::default());
+    }
+
+    #[test]
+    fn test_mint_and_burn() {
+        set_context(get_deposit_context(bob(), 1));
+        let mut contract = Token::new(bob(), 1_000u128, None, Some(2_000), None);
+        register_accounts(&mut contract);
+        contract.add_minter(bob());
+
+        contract.mint(carol(), 500);
+        assert_eq!(contract.get_balance(carol()), 500);
+        assert_eq!(contract.get_total_supply(), 1_500);
+
+        contract.burn(200);
+        assert_eq!(contract.get_balance(bob()), 800);
+        assert_eq!(contract.get_total_supply(), 1_300);
+
+        contract.burn_from(carol(), 100);
+        assert_eq!(contract.get_balance(carol()), 400);
+        assert_eq!(contract.get_total_supply(), 1_200);
+    }
+
+    #[test]
+    #[should_panic(expected = "total supply would exceed 2000")]
+    fn test_cannot_mint_over_max_supply() {
+        set_context(get_deposit_context(bob(), 1));
+        let mut contract = Token::new(bob(), 1_000u128, None, Some(2_000), None);
+        register_accounts(&mut contract);
+        contract.add_minter(bob());
+        contract.mint(carol(), 1_001);
+    }
+
+    #[test]
+    #[should_panic(expected = "Only minters can call this method")]
+    fn test_only_minters_mint() {
+        set_context(get_deposit_context(bob(), 1));
+        let mut contract = Token::new(bob(), 1_000u128, None, None, None);
+        register_accounts(&mut contract);
+        contract.mint(bob(), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "TOKEN_ERR_105: Cannot burn 100 tokens")]
+    fn test_cannot_burn_locked() {
+        set_context(get_deposit_context(bob(), 1));
+        let mut contract = Token::new(bob(), 1_000u128, None, None, None);
+        register_accounts(&mut contract);
+        contract.send(carol(), 100, vec![]);
+        set_context(get_deposit_context(carol(), 1));
+        contract.burn(100);
+    }
+
+    #[test]
+    fn test_ledger_returns_errors() {
+        set_context(get_deposit_context(bob(), 1));
+        let mut contract = Token::new(bob(), 1_000u128, None, None, None);
+        register_accounts(&mut contract);
+        let result = contract.ledger.send(bob(), carol(), 1_001, vec![]);
+        assert_eq!(result, Err(TokenError::NotEnoughBalance { amount: 1_001, balance: 1_000 }));
+        assert_eq!(contract.ledger.burn(&bob(), 0, &bob()), Err(TokenError::ZeroAmount));
+        assert_eq!(contract.ledger.rollback(7), Err(TokenError::UnknownTransfer { transfer_id: 7 }));
+        assert_eq!(contract.get_balance(bob()), 1_000);
+    }
+
+    /// Balance, lock and charged storage of an account
+    type AccountState = (Balance, Balance, Option<StorageUsage>);
+
+    /// Everything a failed ledger call must leave as it was
+    fn ledger_state(contract: &Token) -> (Vec<u8>, StorageUsage, Vec<AccountState>) {
+        let accounts = vec![alice(), bob(), carol()];
+        (
+            contract.ledger.try_to_vec().unwrap(),
+            env::storage_usage(),
+            accounts.into_iter().map(|account_id| (
+                contract.ledger.get_balance(&account_id),
+                contract.ledger.get_locked_balance(&account_id),
+                contract.ledger.storage_usages.get(&account_id),
+            )).collect(),
+        )
+    }
+
+    #[test]
+    fn test_failed_transfers_change_nothing() {
+        set_context(get_deposit_context(bob(), 1));
+        let mut contract = Token::new(bob(), 1_000u128, None, None, None);
+        contract.ledger.add_storage_deposit(&bob(), STAKE).unwrap_or_panic();
+        contract.ledger.register_sponsored(&alice(), 0).unwrap_or_panic();
+        let cost = contract.ledger.get_storage_cost();
+        contract.ledger.register(&carol(), cost).unwrap_or_panic();
+        contract.ledger.transfer(bob(), carol(), 500).unwrap_or_panic();
+        contract.ledger.lock(bob(), carol(), 100).unwrap_or_panic();
+
+        set_context(get_context(bob()));
+        let before = ledger_state(&contract);
+        let dave = String::from("dave.near");
+        let invalid = String::from("not valid");
+        let batch = |new_owner_id: &AccountId, amount: Balance| vec![(alice(), 1, vec![]), (new_owner_id.clone(), amount, vec![1])];
+
+        let cases = vec![
+            (contract.ledger.transfer(bob(), invalid.clone(), 1), TokenError::InvalidAccountId { account_id: invalid.clone() }),
+            (contract.ledger.transfer(bob(), alice(), 0), TokenError::ZeroAmount),
+            (contract.ledger.transfer(bob(), bob(), 1), TokenError::SameOwner),
+            (contract.ledger.transfer(bob(), dave.clone(), 1), TokenError::NotRegistered { account_id: dave.clone() }),
+            (contract.ledger.transfer(bob(), alice(), 401), TokenError::NotEnoughBalance { amount: 401, balance: 400 }),
+            (contract.ledger.transfer(carol(), alice(), 550), TokenError::BalanceLocked { amount: 550, balance: 600, locked: 100 }),
+            (contract.ledger.lock(bob(), invalid.clone(), 1).map(|_| ()), TokenError::InvalidAccountId { account_id: invalid.clone() }),
+            (contract.ledger.lock(bob(), alice(), 0).map(|_| ()), TokenError::ZeroAmount),
+            (contract.ledger.lock(bob(), bob(), 1).map(|_| ()), TokenError::SameOwner),
+            (contract.ledger.lock(bob(), dave.clone(), 1).map(|_| ()), TokenError::NotRegistered { account_id: dave.clone() }),
+            (contract.ledger.lock(bob(), alice(), 401).map(|_| ()), TokenError::NotEnoughBalance { amount: 401, balance: 400 }),
+            (contract.ledger.lock(carol(), alice(), 550).map(|_| ()), TokenError::BalanceLocked { amount: 550, balance: 600, locked: 100 }),
+            (contract.ledger.send_batch(bob(), vec![]).map(|_| ()), TokenError::EmptyBatch),
+            (contract.ledger.send_batch(bob(), batch(&invalid, 1)).map(|_| ()), TokenError::InvalidAccountId { account_id: invalid.clone() }),
+            (contract.ledger.send_batch(bob(), batch(&carol(), 0)).map(|_| ()), TokenError::ZeroAmount),
+            (contract.ledger.send_batch(bob(), batch(&bob(), 1)).map(|_| ()), TokenError::SameOwner),
+            (contract.ledger.send_batch(bob(), batch(&dave, 1)).map(|_| ()), TokenError::NotRegistered { account_id: dave.clone() }),
+            (contract.ledger.send_batch(bob(), batch(&carol(), 400)).map(|_| ()), TokenError::NotEnoughBalance { amount: 401, balance: 400 }),
+            (contract.ledger.send_batch(carol(), vec![(alice(), 550, vec![])]).map(|_| ()), TokenError::BalanceLocked { amount: 550, balance: 600, locked: 100 }),
+        ];
+        for (result, error) in cases {
+            assert_eq!(result, Err(error));
+        }
+
+        // carol staked only the minimum, so her records cannot grow
+        let results = vec![
+            contract.ledger.transfer(carol(), alice(), 1),
+            contract.ledger.lock(carol(), alice(), 1).map(|_| ()),
+            contract.ledger.send_batch(carol(), vec![(alice(), 1, vec![])]).map(|_| ()),
+            contract.ledger.transfer_from(&carol(), carol(), bob(), 1),
+        ];
+        for result in results {
+            assert!(matches!(result, Err(TokenError::StorageDepositTooLow { .. })), "{:?}", result);
+        }
+
+        assert_eq!(ledger_state(&contract), before);
+        assert!(get_logs().is_empty(), "{:?}", get_logs());
     }
 
+    #[test]
+    #[should_panic(expected = "Requires attached deposit of exactly 1 yoctoNEAR")]
+    fn test_send_requires_confirmation_deposit() {
+        set_context(get_deposit_context(bob(), 1));
+        let mut contract = Token::new(bob(), 1_000u128, None, None, None);
+        register_accounts(&mut contract);
+        let mut context = get_deposit_context(bob(), 1);
+        context.attached_deposit = 0;
+        set_context(context);
+        contract.send(carol(), 100, vec![]);
+    }
+
+    #[test]
+    fn test_confirmation_deposit_is_accounted() {
+        set_context(get_deposit_context(bob(), 1));
+        let mut contract = Token::new(bob(), 1_000u128, None, None, None);
+        register_accounts(&mut contract);
+        contract.send(carol(), 100, vec![]);
+        assert_eq!(contract.ledger.get_storage_deposit(&bob()), Some(STAKE + 1));
+
+        contract.set_confirmation_deposit(5.into());
+        let mut context = get_deposit_context(bob(), 1);
+        context.attached_deposit = 5;
+        set_context(context);
+        contract.send(carol(), 100, vec![]);
+        assert_eq!(contract.ledger.get_storage_deposit(&bob()), Some(STAKE + 7));
+    }
+
+    /// Deterministic xorshift64 generator, so a failing run can be repeated from its seed
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            let mut x = self.0;
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            self.0 = x;
+            x
+        }
+
+        fn below(&mut self, n: u64) -> u64 {
+            self.next() % n
+        }
+
+        fn pick<'a, T>(&mut self, items: &'a [T]) -> &'a T {
+            &items[self.below(items.len() as u64) as usize]
+        }
+    }
+
+    /// What the ledger should look like, updated by hand alongside each operation
+    struct Model {
+        balances: HashMap<AccountId, Balance>,
+        locked: HashMap<AccountId, Balance>,
+        pending: Vec<(TransferId, AccountId, AccountId, Balance)>,
+    }
+
+    /// Balance and locked balance of each account
+    fn ledger_snapshot(ledger: &Ledger, accounts: &[AccountId]) -> Vec<(Balance, Balance)> {
+        accounts.iter().map(|a| (ledger.get_balance(a), ledger.get_locked_balance(a))).collect()
+    }
+
+    fn check_ledger_invariants(ledger: &Ledger, model: &Model, accounts: &[AccountId], step: &str) {
+        assert_eq!(ledger.get_balance_sum(), ledger.total_supply, "Balances do not sum to total supply at {}", step);
+        for account_id in accounts {
+            let balance = ledger.get_balance(account_id);
+            let locked = ledger.get_locked_balance(account_id);
+            assert!(locked <= balance, "{} has {} locked of {} at {}", account_id, locked, balance, step);
+            assert_eq!(balance, model.balances[account_id], "Balance of {} at {}", account_id, step);
+            assert_eq!(locked, model.locked[account_id], "Locked balance of {} at {}", account_id, step);
+        }
+    }
+
+    /// Drive send, finalise, partial finalise and rollback in a random order and check the ledger after each step
+    fn run_random_ledger_operations(seed: u64, steps: usize) {
+        reset();
+        set_context(get_deposit_context(bob(), 1));
+        let total_supply = 1_000_000u128;
+        let mut contract = Token::new(bob(), total_supply, None, None, None);
+        let accounts = vec![bob(), alice(), carol(), "dave.near".to_string(), "erin.near".to_string()];
+        contract.ledger.add_storage_deposit(&bob(), STAKE).unwrap_or_panic();
+        for account_id in accounts.iter().skip(1) {
+            contract.ledger.register_sponsored(account_id, STAKE).unwrap_or_panic();
+        }
+        let ledger = &mut contract.ledger;
+
+        let mut model = Model {
+            balances: accounts.iter().map(|a| (a.clone(), 0)).collect(),
+            locked: accounts.iter().map(|a| (a.clone(), 0)).collect(),
+            pending: vec![],
+        };
+        model.balances.insert(bob(), total_supply);
+
+        let mut rng = Rng(seed);
+        for step in 0..steps {
+            let step = format!("seed {} step {}", seed, step);
+            // Fresh context for each step, as the promises of sends use up the gas of one call
+            set_context(get_deposit_context(bob(), 1));
+            match rng.below(6) {
+                // Send, sometimes more than is available
+                0 | 1 => {
+                    let sender_id = rng.pick(&accounts).clone();
+                    let receiver_id = rng.pick(&accounts).clone();
+                    if sender_id == receiver_id {
+                        continue;
+                    }
+                    let available = model.balances[&sender_id] - model.locked[&sender_id];
+                    let amount = 1 + rng.below(available as u64 + 1) as Balance;
+                    let before = ledger_snapshot(ledger, &accounts);
+                    match ledger.send(sender_id.clone(), receiver_id.clone(), amount, vec![]) {
+                        Ok(transfer_id) => {
+                            assert!(amount <= available, "Sent {} with only {} available at {}", amount, available, step);
+                            *model.balances.get_mut(&sender_id).unwrap() -= amount;
+                            *model.balances.get_mut(&receiver_id).unwrap() += amount;
+                            *model.locked.get_mut(&receiver_id).unwrap() += amount;
+                            model.pending.push((transfer_id, sender_id, receiver_id, amount));
+                        },
+                        Err(_) => {
+                            assert!(amount > available, "Could not send {} with {} available at {}", amount, available, step);
+                            assert_eq!(ledger_snapshot(ledger, &accounts), before, "Failed send changed balances at {}", step);
+                        },
+                    }
+                },
+                // Finalise, possibly refunding part of the amount
+                2 | 3 => {
+                    if model.pending.is_empty() {
+                        continue;
+                    }
+                    let index = rng.below(model.pending.len() as u64) as usize;
+                    let (transfer_id, sender_id, receiver_id, amount) = model.pending.remove(index);
+                    let refund = if rng.below(2) == 0 {
+                        ledger.finalise(transfer_id).unwrap_or_panic();
+                        0
+                    } else {
+                        let amount_used = rng.below(amount as u64 + 1) as Balance;
+                        ledger.finalise_partial(transfer_id, amount_used).unwrap_or_panic();
+                        amount - amount_used
+                    };
+                    *model.locked.get_mut(&receiver_id).unwrap() -= amount;
+                    *model.balances.get_mut(&receiver_id).unwrap() -= refund;
+                    *model.balances.get_mut(&sender_id).unwrap() += refund;
+                },
+                // Roll back
+                4 => {
+                    if model.pending.is_empty() {
+                        continue;
+                    }
+                    let index = rng.below(model.pending.len() as u64) as usize;
+                    let (transfer_id, sender_id, receiver_id, amount) = model.pending.remove(index);
+                    ledger.rollback(transfer_id).unwrap_or_panic();
+                    *model.locked.get_mut(&receiver_id).unwrap() -= amount;
+                    *model.balances.get_mut(&receiver_id).unwrap() -= amount;
+                    *model.balances.get_mut(&sender_id).unwrap() += amount;
+                },
+                // Send and roll back right away, which must restore the exact state
+                _ => {
+                    let sender_id = rng.pick(&accounts).clone();
+                    let receiver_id = rng.pick(&accounts).clone();
+                    let available = model.balances[&sender_id] - model.locked[&sender_id];
+                    if sender_id == receiver_id || available == 0 {
+                        continue;
+                    }
+                    let amount = 1 + rng.below(available as u64) as Balance;
+                    let before = ledger_snapshot(ledger, &accounts);
+                    let holder_count = ledger.get_holder_count();
+                    let transfer_id = ledger.send(sender_id, receiver_id.clone(), amount, vec![]).unwrap_or_panic();
+                    ledger.rollback(transfer_id).unwrap_or_panic();
+                    assert_eq!(ledger_snapshot(ledger, &accounts), before, "Rollback did not restore balances at {}", step);
+                    assert_eq!(ledger.get_holder_count(), holder_count, "Rollback did not restore holders at {}", step);
+                    assert!(!ledger.is_pending_for(&receiver_id, transfer_id), "Rollback left the transfer pending at {}", step);
+                },
+            }
+            check_ledger_invariants(ledger, &model, &accounts, &step);
+        }
+
+        // Everything still in flight completes and no tokens stay locked
+        for (transfer_id, _, _, _) in model.pending.drain(..) {
+            ledger.rollback(transfer_id).unwrap_or_panic();
+        }
+        for account_id in accounts.iter() {
+            assert_eq!(ledger.get_locked_balance(account_id), 0, "{} has tokens locked after seed {}", account_id, seed);
+        }
+        assert_eq!(ledger.get_balance_sum(), total_supply);
+    }
+
+    #[test]
+    fn test_ledger_invariants() {
+        for seed in 1..=20 {
+            run_random_ledger_operations(seed, 200);
+        }
+    }
 }
@@ -1,17 +1,30 @@
 use near_sdk::{env, PromiseResult};
+use near_sdk::serde::de::DeserializeOwned;
+use near_sdk::serde_json;
 
 pub fn assert_self() {
     assert_eq!(env::predecessor_account_id(), env::current_account_id());
 }
 
 pub fn is_promise_success() -> bool {
+    assert_eq!(
+        env::promise_results_count(),
+        1,
+        "Contract expected a result on the callback"
+    );
+    matches!(env::promise_result(0), PromiseResult::Successful(_))
+}
+
+/// Decode the JSON value returned by the promise the callback was waiting for.
+/// Returns None if the promise failed.
+pub fn get_promise_result<T: DeserializeOwned>() -> Option<Result<T, serde_json::Error>> {
     assert_eq!(
         env::promise_results_count(),
         1,
         "Contract expected a result on the callback"
     );
     match env::promise_result(0) {
-        PromiseResult::Successful(_) => true,
-        _ => false,
+        PromiseResult::Successful(value) => Some(serde_json::from_slice(&value)),
+        _ => None,
     }
-}
\ No newline at end of file
+}
@@ -1,12 +1,19 @@
-// Hand-written ABI methods for the contracts
+
+// View and change methods of the contracts, as near-api-js wants them.
+// Generated from the Rust sources with `cargo run -p nep9000_abi --bin write_abi`, do not edit by hand.
+import generated from '../contract/abi/abi.json';
+
+function contractMethods(contract) {
+    return {
+        viewMethods: contract.methods.filter(m => m.view).map(m => m.name),
+        changeMethods: contract.methods.filter(m => !m.view).map(m => m.name)
+    };
+}
+
 export const abi = {
-    pool: {
-        viewMethods: ['get_total_received', 'is_receiver'],
-        changeMethods: ['new', 'on_token_received']
-    },
-
-    token: {
-        viewMethods: ['get_total_supply', 'get_balance', 'get_locked_balance', 'get_rollback_count'],
-        changeMethods: ['new', 'send', 'process_bytes']
-    }
-};
\ No newline at end of file
+    pool: contractMethods(generated.pool),
+    token: contractMethods(generated.token)
+};
+
+// Argument names, JSON types and payable flags of each method
+export { generated as fullAbi };
@@ -1,9 +1,12 @@
 import BN from 'bn.js';
 import { abi } from './abi';
-import { createAccount, setUpTestConnection, deployContract, generateUniqueString } from './test-utils';
+import { createAccount, setUpTestConnection, deployContract, registerAccount, stakeStorage, generateUniqueString } from './test-utils';
 
 const TRANSFER_GAS = new BN("300000000000000");
 
+// send() and admin methods need exactly 1 yoctoNEAR to prove a full access key signed the call
+const CONFIRMATION_DEPOSIT = new BN("1");
+
 let near;
 
 // Normal user accounts
@@ -60,6 +63,9 @@ test('Pool accounts received tokens', async () => {
 
     const poolContract = await deployContract(deployer, generateUniqueString('cnt'), 'pool', abi.pool);
     await poolContract.new({ token_id: tokenContract.contractId });
+    await registerAccount(deployer, tokenContract, poolContract.contractId);
+    await stakeStorage(deployer, tokenContract, vitalik.accountId);
+    await stakeStorage(deployer, tokenContract, poolContract.contractId);
 
     const result = await vitalik.functionCall(
         tokenContract.contractId,
@@ -69,7 +75,8 @@ test('Pool accounts received tokens', async () => {
             amount: 5000,
             message: []
         },
-        TRANSFER_GAS
+        TRANSFER_GAS,
+        CONFIRMATION_DEPOSIT,
     );
 
     // Pool controls tokens now
@@ -79,7 +86,17 @@ test('Pool accounts received tokens', async () => {
     const received = await poolContract.get_total_received();
     expect(received).toEqual(5000);
 
+    // Pool destroys what it received
+    await vitalik.functionCall(poolContract.contractId, "burn_received", {}, TRANSFER_GAS);
+
+    const burned = await poolContract.get_total_burned();
+    expect(burned).toEqual(5000);
 
+    const totalSupply = await tokenContract.get_total_supply();
+    expect(totalSupply).toEqual(5000);
+
+    const poolBalance = await tokenContract.get_balance({ owner_id: poolContract.contractId });
+    expect(poolBalance).toEqual(0);
 });
 
 
@@ -97,6 +114,8 @@ test('Send rolld back gracefully in the case of a promise error', async () => {
 
     // This pool does not support receiving tokens from the tokenContract
     await poolContract.new({ token_id: tokenContract2.contractId });
+    await registerAccount(deployer, tokenContract, poolContract.contractId);
+    await stakeStorage(deployer, tokenContract, vitalik.accountId);
 
     const result = await vitalik.functionCall(
         tokenContract.contractId,
@@ -106,12 +125,19 @@ test('Send rolld back gracefully in the case of a promise error', async () => {
             amount: 5000,
             message: []
         },
-        TRANSFER_GAS
+        TRANSFER_GAS,
+        CONFIRMATION_DEPOSIT,
     );
 
     const rollbacks = await tokenContract.get_rollback_count();
     expect(rollbacks).toEqual(1);
 
+    // The pool told why it did not accept the tokens
+    const transferId = JSON.parse(Buffer.from(result.status.SuccessValue, 'base64').toString());
+    const transfer = await tokenContract.get_transfer({ transfer_id: transferId });
+    expect(transfer.status).toEqual('RolledBack');
+    expect(transfer.result.Rejected.code).toEqual(1000);
+
     // Check we rolled back the transaction data correctly
     const balance = await tokenContract.get_balance({ owner_id: poolContract.contractId });
     expect(balance).toEqual(0);
@@ -126,3 +152,42 @@ test('Send rolld back gracefully in the case of a promise error', async () => {
     expect(originalBalance).toEqual(10000);
 
 });
+
+
+test('Pool refunds tokens over its capacity', async () => {
+
+    const tokenContract = await deployContract(deployer, generateUniqueString('cnt'), 'token', abi.token);
+    await tokenContract.new({
+        // Vitalik owns us
+        owner_id: vitalik.accountId,
+        total_supply: 10000,
+    });
+
+    const poolContract = await deployContract(deployer, generateUniqueString('cnt'), 'pool', abi.pool);
+    await poolContract.new({ token_id: tokenContract.contractId, capacity: 3000 });
+    await registerAccount(deployer, tokenContract, poolContract.contractId);
+    await stakeStorage(deployer, tokenContract, vitalik.accountId);
+
+    const result = await vitalik.functionCall(
+        tokenContract.contractId,
+        "send",
+        {
+            new_owner_id: poolContract.contractId,
+            amount: 5000,
+            message: []
+        },
+        TRANSFER_GAS,
+        CONFIRMATION_DEPOSIT,
+    );
+
+    const transferId = JSON.parse(Buffer.from(result.status.SuccessValue, 'base64').toString());
+    const transfer = await tokenContract.get_transfer({ transfer_id: transferId });
+    expect(transfer.status).toEqual('Finalised');
+    expect(transfer.refunded).toEqual('2000');
+
+    const balance = await tokenContract.get_balance({ owner_id: poolContract.contractId });
+    expect(balance).toEqual(3000);
+
+    const originalBalance = await tokenContract.get_balance({ owner_id: vitalik.accountId });
+    expect(originalBalance).toEqual(7000);
+});
@@ -56,6 +56,29 @@ async function deployContract(workingAccount, contractId, contractName, abi) {
     return contract;
 }
 
+// Stake storage on the token contract so the account can hold a balance
+async function registerAccount(workingAccount, tokenContract, accountId) {
+    const bounds = await tokenContract.storage_balance_bounds();
+    await workingAccount.functionCall(
+        tokenContract.contractId,
+        "storage_deposit",
+        { account_id: accountId, registration_only: true },
+        undefined,
+        new BN(bounds.min),
+    );
+}
+
+// Stake storage for the balance history the account writes when it sends tokens, 1 NEAR
+async function stakeStorage(workingAccount, tokenContract, accountId) {
+    await workingAccount.functionCall(
+        tokenContract.contractId,
+        "storage_deposit",
+        { account_id: accountId },
+        undefined,
+        new BN("1000000000000000000000000"),
+    );
+}
+
 function sleep(time) {
     return new Promise(function (resolve) {
         setTimeout(resolve, time);
@@ -76,6 +99,8 @@ module.exports = {
     generateUniqueString,
     createAccount,
     deployContract,
+    registerAccount,
+    stakeStorage,
     sleep,
     ensureDir
 };
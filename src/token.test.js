@@ -1,9 +1,12 @@
 import BN from 'bn.js';
 import { abi } from './abi';
-import { createAccount, setUpTestConnection, deployContract, generateUniqueString } from './test-utils';
+import { createAccount, setUpTestConnection, deployContract, registerAccount, stakeStorage, generateUniqueString } from './test-utils';
 
 const TRANSFER_GAS = new BN("300000000000000");
 
+// send() and admin methods need exactly 1 yoctoNEAR to prove a full access key signed the call
+const CONFIRMATION_DEPOSIT = new BN("1");
+
 // NEAR connection
 let near;
 
@@ -48,6 +51,34 @@ test('Deploy token contract', async () => {
 });
 
 
+test('Owner can update metadata', async () => {
+    const tokenContract = await deployContract(deployer, generateUniqueString('cnt'), 'token', abi.token);
+
+    await tokenContract.new({
+        // Vitalik owns us
+        owner_id: vitalik.accountId,
+        total_supply: 10000,
+        metadata: { name: 'Advanced Fungible', symbol: 'NEP9000', web_link: '', metadata_link: '', decimals: 2 },
+    });
+
+    expect(await tokenContract.get_name()).toEqual('Advanced Fungible');
+
+    await vitalik.functionCall(
+        tokenContract.contractId,
+        "update_metadata",
+        {
+            metadata: { name: 'Advanced Fungible', symbol: 'AFT', web_link: 'https://example.com', metadata_link: '', decimals: 2 },
+        },
+        undefined,
+        CONFIRMATION_DEPOSIT,
+    );
+
+    const metadata = await tokenContract.get_metadata();
+    expect(metadata.symbol).toEqual('AFT');
+    expect(metadata.web_link).toEqual('https://example.com');
+});
+
+
 test('Can send between accounts', async () => {
 
     const tokenContract = await deployContract(deployer, generateUniqueString('cnt'), 'token', abi.token);
@@ -58,6 +89,11 @@ test('Can send between accounts', async () => {
         total_supply: 10000,
     });
 
+    // Gavin must stake storage before receiving tokens,
+    // and Vitalik pays for the balance history of the transfer
+    await registerAccount(deployer, tokenContract, gavin.accountId);
+    await stakeStorage(deployer, tokenContract, vitalik.accountId);
+
     // Vitalik calls token.send()
     const result = await vitalik.functionCall(
         tokenContract.contractId,
@@ -69,9 +105,14 @@ test('Can send between accounts', async () => {
             notify: false
         },
         TRANSFER_GAS,
+        CONFIRMATION_DEPOSIT,
     )
 
-    expect(result.status?.SuccessValue).toBe('');
+    // send() returns the transfer id of the promise chain
+    const transferId = JSON.parse(Buffer.from(result.status.SuccessValue, 'base64').toString());
+    const transfer = await tokenContract.get_transfer({ transfer_id: transferId });
+    expect(transfer.status).toEqual('Finalised');
+    expect(transfer.receiver_id).toEqual(gavin.accountId);
 
     // The initial owner has everything
     const balance = await tokenContract.get_balance({ owner_id: vitalik.accountId });
@@ -95,6 +136,7 @@ test('Cannot send too much', async () => {
         owner_id: vitalik.accountId,
         total_supply: 10000,
     });
+    await registerAccount(deployer, tokenContract, gavin.accountId);
 
     try {
         await vitalik.functionCall(
@@ -105,7 +147,9 @@ test('Cannot send too much', async () => {
                 amount: 11000,
                 message: [],
                 notify: false
-            }
+            },
+            undefined,
+            CONFIRMATION_DEPOSIT,
         )
         throw new Error("Not reached");
     } catch(e) {